@@ -1,65 +1,194 @@
+use anyhow::{anyhow, Result};
 use handlebars::{no_escape, Handlebars};
-use serde::Serialize;
-use std::collections::HashMap;
+use parsers::{
+    dependencies::Dependencies, instantiate::{is_identifier, Instance}, resolve::resolve, ASTNode, Clause, ClauseIntro, ControllerSelector, Count, Definition, EdgeSelection, Location, Marker, MarkerExpr, Policy, PolicyBody,
+    PolicyScope, Relation, Variable, VariableIntro,
+};
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::io::Result;
+
+const TEMPLATE_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../templates");
 
 const BASE_TEMPLATE: &str = "base";
 const ALWAYS_TEMPLATE: &str = "always";
 const SOMETIMES_TEMPLATE: &str = "sometimes";
+const IN_CTRLER_TEMPLATE: &str = "in-ctrler";
+const NAMED_CTRLERS_TEMPLATE: &str = "named-ctrlers";
+const MATCHING_CTRLERS_TEMPLATE: &str = "matching-ctrlers";
+const EXCEPT_CTRLERS_TEMPLATE: &str = "except-ctrlers";
+const KNOWN_CTRLERS_TEMPLATE: &str = "known-ctrlers";
+const GLOB_MATCHES_TEMPLATE: &str = "glob-matches";
+const POLICY_FN_TEMPLATE: &str = "policy-fn";
+const DEFINITION_TEMPLATE: &str = "definition";
 const ALL_VAR_INTRO_TEMPLATE: &str = "all-var-intro";
 const SOME_VAR_INTRO_TEMPLATE: &str = "some-var-intro";
+const NO_VAR_INTRO_TEMPLATE: &str = "no-var-intro";
+const COUNT_VAR_INTRO_TEMPLATE: &str = "count-var-intro";
+const ROOTS_TEMPLATE: &str = "roots";
+const MARKED_TEMPLATE: &str = "marked";
+const TYPE_MARKED_TEMPLATE: &str = "type-marked";
+const SOURCE_OF_TEMPLATE: &str = "source-of";
+const MARKED_TYPES_TEMPLATE: &str = "marked-types";
+const TYPE_HAS_MARKER_TEMPLATE: &str = "type-has-marker";
+const FILTERED_TYPES_TEMPLATE: &str = "filtered-types";
+const MORE_TYPES_TEMPLATE: &str = "more-types";
+const DEFINITION_NODES_TEMPLATE: &str = "definition-nodes";
+const FILTERED_NODES_TEMPLATE: &str = "filtered-nodes";
 const FLOWS_TO_TEMPLATE: &str = "flows-to";
+const NO_FLOWS_TO_TEMPLATE: &str = "no-flows-to";
+const INFLUENCES_TEMPLATE: &str = "influences";
 const CONTROL_FLOW_TEMPLATE: &str = "control-flow";
+const NO_CONTROL_FLOW_TEMPLATE: &str = "no-control-flow";
+const ASSOCIATED_CALL_SITE_TEMPLATE: &str = "associated-call-site";
+const HAPPENS_BEFORE_TEMPLATE: &str = "happens-before";
+const IS_MARKED_TEMPLATE: &str = "is-marked";
+const IS_NOT_MARKED_TEMPLATE: &str = "is-not-marked";
 const THROUGH_TEMPLATE: &str = "through";
 const IMPLIES_TEMPLATE: &str = "implies";
+const IMPLIES_OTHERWISE_TEMPLATE: &str = "implies-otherwise";
+const UNLESS_TEMPLATE: &str = "unless";
 const AND_TEMPLATE: &str = "and";
 const OR_TEMPLATE: &str = "or";
-const NODES_TEMPLATE: &str = "nodes";
+
+// Names that are in scope while traversing the policy.
+// Variables are checked by parsers::resolve before anything is compiled.
+struct Env<'a> {
+    // names of definitions, which can be quantified over like markers
+    definitions: HashSet<&'a str>,
+    // definitions with parameters, which are instantiated where they're used
+    templates: Vec<&'a Definition<'a>>,
+}
+
+// A mistake in the policy, reported at the location of the offending node
+#[derive(Debug)]
+struct CompileError {
+    location: Location,
+    message: String,
+    // the imported file the node came from; None for the policy file itself
+    file: Option<String>,
+}
+
+type CompileResult<T> = std::result::Result<T, CompileError>;
+
+fn relation_to_template<'a>(relation: &'a Relation<'a>) -> &'a str {
+    match relation {
+        Relation::Influences(_) => INFLUENCES_TEMPLATE,
+        Relation::FlowsTo(_) => FLOWS_TO_TEMPLATE,
+        Relation::NoFlowsTo(_) => NO_FLOWS_TO_TEMPLATE,
+        Relation::ControlFlow(_) => CONTROL_FLOW_TEMPLATE,
+        Relation::NoControlFlow(_) => NO_CONTROL_FLOW_TEMPLATE,
+        Relation::AssociatedCallSite(_) => ASSOCIATED_CALL_SITE_TEMPLATE,
+        Relation::HappensBefore(_) | Relation::HappensAfter(_) => HAPPENS_BEFORE_TEMPLATE,
+        Relation::IsMarked(_) => IS_MARKED_TEMPLATE,
+        Relation::IsNotMarked(_) => IS_NOT_MARKED_TEMPLATE,
+        Relation::OnlyVia(_) => THROUGH_TEMPLATE,
+    }
+}
+
+// The Paralegal EdgeType that selects the same edges
+fn edge_type(edges: &EdgeSelection) -> &'static str {
+    match edges {
+        EdgeSelection::Data => "Data",
+        EdgeSelection::Control => "Control",
+        EdgeSelection::DataAndControl => "DataAndControl",
+    }
+}
 
 fn node_to_template<'a>(node: &'a ASTNode<'a>) -> &'a str {
     match node {
-        ASTNode::FlowsTo(_) => FLOWS_TO_TEMPLATE,
-        ASTNode::ControlFlow(_) => CONTROL_FLOW_TEMPLATE,
-        ASTNode::OnlyVia(_) => THROUGH_TEMPLATE,
+        ASTNode::Relation(relation) => relation_to_template(relation),
         ASTNode::And(_) => AND_TEMPLATE,
         ASTNode::Or(_) => OR_TEMPLATE,
-        // ASTNode::Implies(_) => IMPLIES_TEMPLATE,
-        ASTNode::VarIntroduction(ob) => {
-            match ob.binding.quantifier {
-                Quantifier::All => ALL_VAR_INTRO_TEMPLATE,
-                Quantifier::Some => SOME_VAR_INTRO_TEMPLATE,
+        ASTNode::Conditional(_) => IMPLIES_TEMPLATE,
+        ASTNode::Clause(clause) => {
+            match clause.intro {
+                ClauseIntro::ForEach(_) => ALL_VAR_INTRO_TEMPLATE,
+                ClauseIntro::ThereIs(_) => SOME_VAR_INTRO_TEMPLATE,
+                ClauseIntro::ThereIsNo(_) => NO_VAR_INTRO_TEMPLATE,
+                ClauseIntro::Counted(_) => COUNT_VAR_INTRO_TEMPLATE,
+                ClauseIntro::Conditional(_) if clause.otherwise.is_some() => IMPLIES_OTHERWISE_TEMPLATE,
+                ClauseIntro::Conditional(_) => IMPLIES_TEMPLATE,
+                // Unless c: b, Otherwise: d is If c then: d, Otherwise: b
+                ClauseIntro::Unless(_) if clause.otherwise.is_some() => IMPLIES_OTHERWISE_TEMPLATE,
+                ClauseIntro::Unless(_) => UNLESS_TEMPLATE,
             }
         }
     }
 }
 
-fn scope_to_template(scope : &PolicyScope) -> &str {
+fn intro_to_template<'a>(intro: &'a VariableIntro<'a>) -> &'a str {
+    match intro {
+        VariableIntro::Roots => ROOTS_TEMPLATE,
+        VariableIntro::Variable(_) => DEFINITION_NODES_TEMPLATE,
+        VariableIntro::VariableMarked(_) => MARKED_TEMPLATE,
+        VariableIntro::VariableOfTypeMarked(_) => TYPE_MARKED_TEMPLATE,
+        VariableIntro::VariableSourceof(_) => SOURCE_OF_TEMPLATE,
+    }
+}
+
+fn scope_to_template<'a>(scope : &'a PolicyScope<'a>) -> &'a str {
     match scope {
         PolicyScope::Always => ALWAYS_TEMPLATE,
-        PolicyScope::Sometimes => SOMETIMES_TEMPLATE
+        PolicyScope::Sometimes => SOMETIMES_TEMPLATE,
+        PolicyScope::InCtrler(_) => IN_CTRLER_TEMPLATE,
+    }
+}
+
+fn selector_to_template<'a>(selector: &'a ControllerSelector<'a>) -> &'a str {
+    match selector {
+        ControllerSelector::Named(_) => NAMED_CTRLERS_TEMPLATE,
+        ControllerSelector::Matching(_) => MATCHING_CTRLERS_TEMPLATE,
+        ControllerSelector::Except(_) => EXCEPT_CTRLERS_TEMPLATE,
     }
 }
 
 fn register_templates(handlebars: &mut Handlebars) {
     let templates: Vec<(&str, &str)> = Vec::from([
-        (BASE_TEMPLATE, "templates/policy.handlebars"),
-        (ALL_VAR_INTRO_TEMPLATE, "templates/astnodes/all-intro.handlebars"),
-        (SOME_VAR_INTRO_TEMPLATE, "templates/astnodes/some-intro.handlebars"),
-        (FLOWS_TO_TEMPLATE, "templates/astnodes/flows-to.handlebars"),
-        (CONTROL_FLOW_TEMPLATE, "templates/astnodes/control-flow.handlebars"),
-        (THROUGH_TEMPLATE, "templates/astnodes/through.handlebars"),
-        (AND_TEMPLATE, "templates/astnodes/and.handlebars"),
-        (OR_TEMPLATE, "templates/astnodes/or.handlebars"),
-        (IMPLIES_TEMPLATE, "templates/astnodes/implies.handlebars"),
-        (ALWAYS_TEMPLATE, "templates/scope/always.handlebars"),
-        (SOMETIMES_TEMPLATE, "templates/scope/sometimes.handlebars"),
-        (NODES_TEMPLATE, "templates/nodes.handlebars")
+        (BASE_TEMPLATE, "policy.handlebars"),
+        (POLICY_FN_TEMPLATE, "policy-fn.handlebars"),
+        (DEFINITION_TEMPLATE, "definition.handlebars"),
+        (ALL_VAR_INTRO_TEMPLATE, "astnodes/all-intro.handlebars"),
+        (SOME_VAR_INTRO_TEMPLATE, "astnodes/some-intro.handlebars"),
+        (NO_VAR_INTRO_TEMPLATE, "astnodes/none-intro.handlebars"),
+        (COUNT_VAR_INTRO_TEMPLATE, "astnodes/count-intro.handlebars"),
+        (FLOWS_TO_TEMPLATE, "astnodes/flows-to.handlebars"),
+        (NO_FLOWS_TO_TEMPLATE, "astnodes/no-flows-to.handlebars"),
+        (INFLUENCES_TEMPLATE, "astnodes/influences.handlebars"),
+        (CONTROL_FLOW_TEMPLATE, "astnodes/control-flow.handlebars"),
+        (NO_CONTROL_FLOW_TEMPLATE, "astnodes/no-control-flow.handlebars"),
+        (ASSOCIATED_CALL_SITE_TEMPLATE, "astnodes/associated-call-site.handlebars"),
+        (HAPPENS_BEFORE_TEMPLATE, "astnodes/happens-before.handlebars"),
+        (IS_MARKED_TEMPLATE, "astnodes/is-marked.handlebars"),
+        (IS_NOT_MARKED_TEMPLATE, "astnodes/is-not-marked.handlebars"),
+        (THROUGH_TEMPLATE, "astnodes/through.handlebars"),
+        (AND_TEMPLATE, "astnodes/and.handlebars"),
+        (OR_TEMPLATE, "astnodes/or.handlebars"),
+        (IMPLIES_TEMPLATE, "astnodes/implies.handlebars"),
+        (IMPLIES_OTHERWISE_TEMPLATE, "astnodes/implies-otherwise.handlebars"),
+        (UNLESS_TEMPLATE, "astnodes/unless.handlebars"),
+        (ROOTS_TEMPLATE, "intros/roots.handlebars"),
+        (MARKED_TEMPLATE, "intros/marked.handlebars"),
+        (TYPE_MARKED_TEMPLATE, "intros/type-marked.handlebars"),
+        (SOURCE_OF_TEMPLATE, "intros/source-of.handlebars"),
+        (MARKED_TYPES_TEMPLATE, "intros/types/marked.handlebars"),
+        (TYPE_HAS_MARKER_TEMPLATE, "intros/types/has-marker.handlebars"),
+        (FILTERED_TYPES_TEMPLATE, "intros/types/filtered.handlebars"),
+        (MORE_TYPES_TEMPLATE, "intros/types/more.handlebars"),
+        (DEFINITION_NODES_TEMPLATE, "intros/definition.handlebars"),
+        (FILTERED_NODES_TEMPLATE, "intros/filtered.handlebars"),
+        (ALWAYS_TEMPLATE, "scope/always.handlebars"),
+        (SOMETIMES_TEMPLATE, "scope/sometimes.handlebars"),
+        (IN_CTRLER_TEMPLATE, "scope/in-ctrler.handlebars"),
+        (NAMED_CTRLERS_TEMPLATE, "scope/selectors/named.handlebars"),
+        (MATCHING_CTRLERS_TEMPLATE, "scope/selectors/matching.handlebars"),
+        (EXCEPT_CTRLERS_TEMPLATE, "scope/selectors/except.handlebars"),
+        (KNOWN_CTRLERS_TEMPLATE, "scope/known-ctrlers.handlebars"),
+        (GLOB_MATCHES_TEMPLATE, "scope/glob-matches.handlebars"),
     ]);
-    
+
     for (name, path) in templates {
         handlebars
-        .register_template_file(name, path)
+        .register_template_file(name, format!("{TEMPLATE_DIR}/{path}"))
         .expect(&format!(
             "Could not register {name} template with handlebars"
         ));
@@ -76,86 +205,834 @@ fn render_template<'a, T: serde::Serialize, U: serde::Serialize>(
         .expect(&format!("Could not render {name} handlebars template"))
 }
 
-fn verify_vars_in_scope<'a>(vars : Vec<&Variable<'a>>, env : &HashMap<Variable<'a>, Marker<'a>>) {
-    for var in vars {
-        if !env.contains_key(var) {
-            let msg = format!("Cannot reference variable {}; it has not been introduced", {var});
-            panic!("{}", msg);
+// Policy variables are quoted strings like "stored commit" or "user's email";
+// turn them into something that can be part of a Rust identifier.
+// Characters outside of ASCII are spelled out by code point, e.g. "données" is `donn_ue9_es`.
+// Different names can still end up the same, like "user's email" and "user s email"; see check_idents.
+fn rust_ident(name: &str) -> String {
+    name.split_whitespace()
+        .collect::<Vec<_>>()
+        .join("_")
+        .chars()
+        .map(|c| match c {
+            c if c.is_ascii_alphanumeric() || c == '_' => c.to_string(),
+            c if c.is_ascii() => "_".to_string(),
+            c => format!("_u{:x}_", c as u32),
+        })
+        .collect()
+}
+
+// Two names that make the same identifier would be the same Rust variable,
+// so one could silently shadow the other; reject them instead.
+fn check_idents<'a>(policy: &Policy<'a>) -> CompileResult<()> {
+    let mut seen: HashMap<String, Variable<'a>> = HashMap::new();
+    let definitions = policy.definitions.iter().map(|definition| {
+        let mut variables = vec![definition.variable];
+        variables.extend(definition.declaration.variables());
+        variables.extend(definition.filter.variables());
+        (definition.imported_from, variables)
+    });
+    let bodies = policy.bodies.iter().map(|body| (None, body.body.variables()));
+    for (file, variables) in definitions.chain(bodies) {
+        for var in variables {
+            match seen.get(&rust_ident(var.name)) {
+                Some(earlier) if earlier.name != var.name => {
+                    return Err(CompileError {
+                        location: var.location,
+                        message: format!(
+                            "variable \"{}\" would have the same Rust name as variable \"{}\" at {}; rename one of them",
+                            var.name, earlier.name, earlier.location
+                        ),
+                        file: file.map(str::to_string),
+                    });
+                },
+                Some(_) => (),
+                None => {
+                    seen.insert(rust_ident(var.name), var);
+                },
+            }
         }
     }
+    Ok(())
+}
+
+// The templates have closure parameters of their own, like `n` and `c_id`,
+// so policy variables get a prefix that none of them start with.
+// The prefix also keeps variables named like Rust keywords, e.g. "type", from being keywords.
+fn variable_to_ident(var: &Variable) -> String {
+    format!("v_{}", rust_ident(var.name))
+}
+
+// The vector of nodes a definition computes is `<ident>_nodes`, with a prefix of its own
+// so that a definition "sink" can't collide with a variable "sink nodes".
+fn definition_to_ident(var: &Variable) -> String {
+    format!("d_{}", rust_ident(var.name))
+}
+
+// Parameters like <m> are replaced when a definition template is used, so anywhere else they're a mistake
+fn marker_name<'a>(marker: &Marker<'a>) -> CompileResult<&'a str> {
+    if marker.name.starts_with('<') {
+        return Err(CompileError {
+            location: marker.location,
+            message: format!("{} is not a parameter here; only a definition with {} in its name can use it", marker.name, marker.name),
+            file: None,
+        });
+    }
+    Ok(marker.name)
+}
+
+// Render the iterator of nodes that a variable introduction ranges over.
+// Returns the variable being introduced, if there is one, and the rendered iterator.
+fn intro_to_nodes<'a>(
+    handlebars: &mut Handlebars,
+    intro: &VariableIntro<'a>,
+    env: &Env<'a>,
+) -> CompileResult<(Option<Variable<'a>>, String)> {
+    let mut map: HashMap<&str, String> = HashMap::new();
+    let variable = match intro {
+        VariableIntro::Roots => None,
+        VariableIntro::Variable(var) => {
+            if !env.definitions.contains(var.name) {
+                let template = env.templates.iter().find_map(|template| Some((*template, template.instantiate(var.name)?)));
+                let Some((template, instance)) = template else {
+                    return Err(CompileError {
+                        location: var.location,
+                        message: format!("variable \"{}\" needs a marker; there is no definition for it", var.name),
+                        file: None,
+                    });
+                };
+                let nodes = instance_to_nodes(handlebars, template, &instance, var, env)
+                    .map_err(|e| CompileError { file: e.file.or(template.imported_from.map(str::to_string)), ..e })?;
+                return Ok((Some(*var), nodes));
+            }
+            map.insert("variable", definition_to_ident(var));
+            Some(*var)
+        },
+        VariableIntro::VariableMarked((var, expr)) => {
+            map.insert("markers", marked_nodes_filter(handlebars, expr)?);
+            Some(*var)
+        },
+        VariableIntro::VariableOfTypeMarked((var, expr)) => {
+            map.insert("types", marked_types(handlebars, expr)?);
+            Some(*var)
+        },
+        VariableIntro::VariableSourceof((var, source_of)) => {
+            map.insert("source_of", variable_to_ident(source_of));
+            Some(*var)
+        }
+    };
+    Ok((variable, render_template(handlebars, &map, intro_to_template(intro))))
+}
+
+// Join conditions with the `and` or `or` template, e.g. for the markers in `marked a or b`
+fn join_conditions(handlebars: &mut Handlebars, conditions: Vec<String>, template: &str) -> String {
+    conditions
+        .into_iter()
+        .reduce(|src, dest| render_template(handlebars, &HashMap::from([("src", src), ("dest", dest)]), template))
+        .unwrap_or_default()
+}
+
+// The condition on a node `n` for `marked <expr>`
+fn marked_nodes_filter<'a>(handlebars: &mut Handlebars, expr: &MarkerExpr<'a>) -> CompileResult<String> {
+    let conditions = expr.markers()
+        .iter()
+        .map(|marker| {
+            let map = HashMap::from([("variable", "*n".to_string()), ("marker", marker_name(marker)?.to_string())]);
+            Ok(render_template(handlebars, &map, IS_MARKED_TEMPLATE))
+        })
+        .collect::<CompileResult<Vec<_>>>()?;
+    Ok(match expr {
+        MarkerExpr::Or(_) => join_conditions(handlebars, conditions, OR_TEMPLATE),
+        MarkerExpr::Marker(_) | MarkerExpr::And(_) => join_conditions(handlebars, conditions, AND_TEMPLATE),
+    })
+}
+
+// The types for `type marked <expr>`, without repeats, since each type's nodes are visited once per time it appears
+fn marked_types<'a>(handlebars: &mut Handlebars, expr: &MarkerExpr<'a>) -> CompileResult<String> {
+    let names = expr.markers().iter().map(marker_name).collect::<CompileResult<Vec<_>>>()?;
+    let mut types = Vec::new();
+    let mut has_marker = Vec::new();
+    for name in names {
+        let map = HashMap::from([("marker", name.to_string())]);
+        types.push(render_template(handlebars, &map, MARKED_TYPES_TEMPLATE));
+        has_marker.push(render_template(handlebars, &map, TYPE_HAS_MARKER_TEMPLATE));
+    }
+    let mut types = types.into_iter();
+    let first = types.next().unwrap_or_default();
+    match expr {
+        MarkerExpr::Marker(_) => Ok(first),
+        // the types of the first marker that also have all of the others
+        MarkerExpr::And(_) => {
+            let filter = join_conditions(handlebars, has_marker[1..].to_vec(), AND_TEMPLATE);
+            Ok(render_template(handlebars, &HashMap::from([("types", first), ("filter", filter)]), FILTERED_TYPES_TEMPLATE))
+        },
+        // the types of each marker that don't have any of the markers before it
+        MarkerExpr::Or(_) => Ok(types.enumerate().fold(first, |acc, (idx, more)| {
+            let earlier = join_conditions(handlebars, has_marker[..=idx].to_vec(), OR_TEMPLATE);
+            render_template(handlebars, &HashMap::from([("types", acc), ("more", more), ("earlier", earlier)]), MORE_TYPES_TEMPLATE)
+        })),
+    }
+}
+
+// A use of a definition template, like "stored sensitive" for "stored <m>", becomes the nodes that satisfy
+// the template's filter with the arguments filled in.
+// Unlike an ordinary definition, it's computed where it's used, since its arguments may be variables bound there.
+fn instance_to_nodes<'a>(
+    handlebars: &mut Handlebars,
+    template: &Definition<'a>,
+    instance: &Instance<'a>,
+    use_site: &Variable<'a>,
+    env: &Env<'a>,
+) -> CompileResult<String> {
+    // mistakes in the arguments are reported where the template is used
+    let argument_error = |message: String| CompileError { location: use_site.location, message, file: None };
+    if let Some(marker) = instance.markers.iter().find(|marker| !is_identifier(marker)) {
+        return Err(argument_error(format!(
+            "\"{marker}\" is not a marker name, but \"{}\" uses it as a marker", template.variable.name
+        )));
+    }
+
+    // the template only sees its own variable and its arguments, plus the definitions before it
+    let earlier = env.templates.iter().take_while(|earlier| !std::ptr::eq(**earlier, template)).copied().collect();
+    let mut template_env = Env {
+        definitions: env.definitions.clone(),
+        templates: earlier,
+    };
+    let (variable, nodes) = intro_to_nodes(handlebars, &instance.declaration, &template_env)?;
+    let variable = variable.ok_or(CompileError {
+        location: template.variable.location,
+        message: "definitions must introduce a variable".to_string(),
+        file: None,
+    })?;
+
+    let mut map: HashMap<&str, String> = HashMap::new();
+    map.insert("nodes", nodes);
+    map.insert("variable", variable_to_ident(&variable));
+    map.insert("filter", traverse_ast(handlebars, &instance.filter, &mut template_env)?);
+    Ok(render_template(handlebars, &map, FILTERED_NODES_TEMPLATE))
+}
+
+fn traverse_relation<'a>(
+    handlebars: &mut Handlebars,
+    relation: &Relation<'a>,
+    env: &Env<'a>,
+) -> CompileResult<String> {
+    let mut map: HashMap<&str, String> = HashMap::new();
+    match relation {
+        Relation::Influences((src, dest, edges))
+        | Relation::FlowsTo((src, dest, edges))
+        | Relation::NoFlowsTo((src, dest, edges)) => {
+            map.insert("src", variable_to_ident(src));
+            map.insert("dest", variable_to_ident(dest));
+            map.insert("edge_type", edge_type(edges).to_string());
+        },
+        Relation::ControlFlow((src, dest))
+        | Relation::NoControlFlow((src, dest))
+        | Relation::AssociatedCallSite((src, dest)) => {
+            map.insert("src", variable_to_ident(src));
+            map.insert("dest", variable_to_ident(dest));
+        },
+        Relation::HappensBefore((src, dest)) | Relation::HappensAfter((src, dest)) => {
+            let (before, after) = match relation {
+                Relation::HappensAfter(_) => (dest, src),
+                _ => (src, dest),
+            };
+            map.insert("roots", render_template(handlebars, &HashMap::<&str, String>::new(), ROOTS_TEMPLATE));
+            map.insert("src", variable_to_ident(before));
+            map.insert("dest", variable_to_ident(after));
+        },
+        Relation::IsMarked((var, marker)) | Relation::IsNotMarked((var, marker)) => {
+            map.insert("variable", variable_to_ident(var));
+            map.insert("marker", marker_name(marker)?.to_string());
+        },
+        Relation::OnlyVia(intros) => {
+            let (src, dest, checkpoint) = &**intros;
+            // each of these intros ranges over its own set of nodes,
+            // so we only need the node iterators, not the variables
+            map.insert("src", intro_to_nodes(handlebars, src, env)?.1);
+            map.insert("dest", intro_to_nodes(handlebars, dest, env)?.1);
+            map.insert("checkpoint", intro_to_nodes(handlebars, checkpoint, env)?.1);
+        }
+    }
+    Ok(render_template(handlebars, &map, relation_to_template(relation)))
+}
+
+// The variable a clause introduces, along with the nodes it ranges over
+fn bind_intro<'a>(
+    handlebars: &mut Handlebars,
+    intro: &VariableIntro<'a>,
+    filter: &Option<ASTNode<'a>>,
+    location: Location,
+    env: &mut Env<'a>,
+) -> CompileResult<(Variable<'a>, String)> {
+    let (variable, mut nodes) = intro_to_nodes(handlebars, intro, env)?;
+    let variable = variable.ok_or(CompileError {
+        location,
+        message: "clauses must introduce a variable".to_string(),
+        file: None,
+    })?;
+    // the filter talks about the variable, so it's rendered along with the intro
+    if let Some(filter) = filter {
+        let mut filter_map: HashMap<&str, String> = HashMap::new();
+        filter_map.insert("nodes", nodes);
+        filter_map.insert("variable", variable_to_ident(&variable));
+        filter_map.insert("filter", traverse_ast(handlebars, filter, env)?);
+        nodes = render_template(handlebars, &filter_map, FILTERED_NODES_TEMPLATE);
+    }
+    Ok((variable, nodes))
+}
+
+// A `there is a` clause in a condition, with its intro and filter
+fn there_is<'n, 'a>(item: &'n ASTNode<'a>) -> Option<(&'n Clause<'a>, &'n VariableIntro<'a>, &'n Option<ASTNode<'a>>)> {
+    match item {
+        ASTNode::Clause(clause) => match &clause.intro {
+            ClauseIntro::ThereIs((intro, filter)) => Some((clause, intro, filter)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// `If there is a "c" where P and Q then: B` holds when every such "c" that satisfies P and Q satisfies B,
+// so it becomes `for each "c", if P and Q then B`, which keeps "c" bound in the body.
+fn lift_condition<'a>(
+    handlebars: &mut Handlebars,
+    mut items: Vec<&ASTNode<'a>>,
+    body: &ASTNode<'a>,
+    env: &mut Env<'a>,
+) -> CompileResult<String> {
+    let quantified = items.iter().enumerate().find_map(|(idx, item)| there_is(item).map(|clause| (idx, clause)));
+    let mut map: HashMap<&str, String> = HashMap::new();
+    let Some((idx, (clause, intro, filter))) = quantified else {
+        let conditions = items.iter().map(|item| traverse_ast(handlebars, item, env)).collect::<CompileResult<Vec<_>>>()?;
+        map.insert("src", join_conditions(handlebars, conditions, AND_TEMPLATE));
+        map.insert("dest", traverse_ast(handlebars, body, env)?);
+        return Ok(render_template(handlebars, &map, IMPLIES_TEMPLATE));
+    };
+
+    let (variable, nodes) = bind_intro(handlebars, intro, filter, clause.location, env)?;
+    // what must hold of the variable takes its place in the condition
+    items.splice(idx..=idx, clause.body.conjuncts());
+    let lifted = lift_condition(handlebars, items, body, env)?;
+
+    map.insert("variable", variable_to_ident(&variable));
+    map.insert("nodes", nodes);
+    map.insert("body", lifted);
+    Ok(render_template(handlebars, &map, ALL_VAR_INTRO_TEMPLATE))
 }
 
 fn traverse_ast<'a>(
     handlebars: &mut Handlebars,
     node: &ASTNode<'a>,
-    env: &mut HashMap<Variable<'a>, Marker<'a>>
-) -> String {
-    let mut map: HashMap<&str, &str> = HashMap::new();
+    env: &mut Env<'a>,
+) -> CompileResult<String> {
+    let mut map: HashMap<&str, String> = HashMap::new();
     match node {
-        ASTNode::FlowsTo(obligation) | ASTNode::ControlFlow(obligation)  => {
-            verify_vars_in_scope(vec![&obligation.src, &obligation.dest], &env);
-            map.insert("src", obligation.src);
-            map.insert("dest", obligation.dest);
-            render_template(handlebars, &map, node_to_template(&node))
-        },
-        ASTNode::OnlyVia(obligation) => {
-            verify_vars_in_scope(vec![&obligation.src, &obligation.dest, &obligation.checkpoint], &env);
-            // come back to this, depends on quantifier
-            todo!()
-        },
-        ASTNode::And(obligation) | ASTNode::Or(obligation) | ASTNode::Implies(obligation) => {
-            let src_res = &traverse_ast(handlebars, &obligation.src, env);
+        ASTNode::Relation(relation) => traverse_relation(handlebars, relation, env),
+        ASTNode::And(obligation) | ASTNode::Or(obligation) | ASTNode::Conditional(obligation) => {
+            let src_res = traverse_ast(handlebars, &obligation.src, env)?;
             map.insert("src", src_res);
-            let dest_res = &traverse_ast(handlebars, &obligation.dest, env);
+            let dest_res = traverse_ast(handlebars, &obligation.dest, env)?;
             map.insert("dest", dest_res);
-            render_template(handlebars, &map, node_to_template(&node))
+            Ok(render_template(handlebars, &map, node_to_template(node)))
         },
-        ASTNode::VarIntroduction(clause) => {
-            if env.contains_key(clause.binding.variable) {
-                panic!("Policy already introduced this variable; choose a different name");
+        ASTNode::Clause(clause) => {
+            match &clause.intro {
+                ClauseIntro::ForEach((intro, filter))
+                | ClauseIntro::ThereIs((intro, filter))
+                | ClauseIntro::ThereIsNo((intro, filter))
+                | ClauseIntro::Counted((_, intro, filter)) => {
+                    let (variable, nodes) = bind_intro(handlebars, intro, filter, clause.location, env)?;
+                    let body = traverse_ast(handlebars, &clause.body, env)?;
+                    map.insert("variable", variable_to_ident(&variable));
+                    map.insert("nodes", nodes);
+                    map.insert("body", body);
+                    if let ClauseIntro::Counted((count, _, _)) = &clause.intro {
+                        let (comparison, n) = match count {
+                            Count::AtLeast(n) => (">=", n),
+                            Count::AtMost(n) => ("<=", n),
+                            Count::Exactly(n) => ("==", n),
+                        };
+                        map.insert("comparison", comparison.to_string());
+                        map.insert("count", n.to_string());
+                    }
+                },
+                // the variables a condition quantifies over stay bound in the body
+                ClauseIntro::Conditional(condition) if clause.otherwise.is_none() => {
+                    return lift_condition(handlebars, condition.conjuncts(), &clause.body, env);
+                },
+                ClauseIntro::Conditional(condition) | ClauseIntro::Unless(condition) => {
+                    let src_res = traverse_ast(handlebars, condition, env)?;
+                    map.insert("src", src_res);
+                    let body = match &clause.intro {
+                        ClauseIntro::Conditional(condition) if condition.conjuncts().iter().any(|item| there_is(item).is_some()) => {
+                            lift_condition(handlebars, condition.conjuncts(), &clause.body, env)?
+                        },
+                        _ => traverse_ast(handlebars, &clause.body, env)?,
+                    };
+                    let otherwise = clause.otherwise.as_ref().map(|otherwise| traverse_ast(handlebars, otherwise, env)).transpose()?;
+                    match (&clause.intro, otherwise) {
+                        // the body holds when the condition doesn't
+                        (ClauseIntro::Unless(_), Some(otherwise)) => {
+                            map.insert("dest", otherwise);
+                            map.insert("otherwise", body);
+                        },
+                        (_, Some(otherwise)) => {
+                            map.insert("dest", body);
+                            map.insert("otherwise", otherwise);
+                        },
+                        (_, None) => {
+                            map.insert("dest", body);
+                        },
+                    }
+                }
             }
-            env.insert(clause.binding.variable, clause.binding.marker);
+            Ok(render_template(handlebars, &map, node_to_template(node)))
+        }
+    }
+}
 
-            let res = &traverse_ast(handlebars, &clause.body, env);
-            map.insert("variable", clause.binding.variable);
-            map.insert("body", res);
+// A definition becomes a vector of the nodes that satisfy its filter.
+// Its variable is only in scope for the filter; afterwards, clauses refer to the definition by name.
+fn compile_definition<'a>(
+    handlebars: &mut Handlebars,
+    definition: &Definition<'a>,
+    env: &mut Env<'a>,
+) -> CompileResult<String> {
+    let (variable, nodes) = intro_to_nodes(handlebars, &definition.declaration, env)?;
+    let variable = variable.ok_or(CompileError {
+        location: definition.variable.location,
+        message: "definitions must introduce a variable".to_string(),
+        file: None,
+    })?;
+    let filter = traverse_ast(handlebars, &definition.filter, env)?;
+    env.definitions.insert(definition.variable.name);
 
-            // if the variable clause closes, the variable is now out of scope, so remove it from the environment
-            env.remove(clause.binding.variable);
+    let mut map: HashMap<&str, String> = HashMap::new();
+    map.insert("name", definition_to_ident(&definition.variable));
+    map.insert("variable", variable_to_ident(&variable));
+    map.insert("nodes", nodes);
+    map.insert("filter", filter);
+    Ok(render_template(handlebars, &map, DEFINITION_TEMPLATE))
+}
 
-            render_template(handlebars, &map, node_to_template(&node))
-        }
+// The filter that picks out the selected controllers, a description of them for when none are found,
+// and checks that any controllers the policy names actually exist.
+fn compile_selector<'a>(
+    handlebars: &mut Handlebars,
+    selector: &ControllerSelector<'a>,
+) -> HashMap<&'static str, String> {
+    let mut map: HashMap<&str, String> = HashMap::new();
+    // Debug formatting quotes and escapes a name, making it a Rust string literal
+    let quoted = |names: &Vec<&str>| names.iter().map(|name| format!("{name:?}")).collect::<Vec<_>>().join(", ");
+    let description = match selector {
+        ControllerSelector::Named(names) => {
+            map.insert("names", quoted(names));
+            format!("named {}", names.join(", "))
+        },
+        ControllerSelector::Matching(pattern) => {
+            map.insert("pattern", format!("{pattern:?}"));
+            format!("matching {pattern}")
+        },
+        ControllerSelector::Except(names) => {
+            map.insert("names", quoted(names));
+            format!("other than {}", names.join(", "))
+        },
+    };
+    let selector_filter = render_template(handlebars, &map, selector_to_template(selector));
+    let checks = match selector {
+        ControllerSelector::Named(_) | ControllerSelector::Except(_) => render_template(handlebars, &map, KNOWN_CTRLERS_TEMPLATE),
+        ControllerSelector::Matching(_) => String::new(),
+    };
+    HashMap::from([
+        ("selector", selector_filter),
+        ("description", format!("{description:?}")),
+        ("checks", checks),
+    ])
+}
+
+// Each body becomes its own policy function, named after the body so that failures are reported per policy.
+// The definitions are computed per controller, so each body gets its own copy.
+fn compile_policy_body<'a>(
+    handlebars: &mut Handlebars,
+    body: &PolicyBody<'a>,
+    definitions: &str,
+    env: &mut Env<'a>,
+) -> CompileResult<(String, String)> {
+    let obligation = traverse_ast(handlebars, &body.body, env)?;
+
+    let mut map: HashMap<&str, String> = HashMap::new();
+    map.insert("definitions", definitions.to_string());
+    map.insert("obligation", obligation);
+    if let PolicyScope::InCtrler(selector) = &body.scope {
+        map.extend(compile_selector(handlebars, selector));
     }
+    let policy_logic = render_template(handlebars, &map, scope_to_template(&body.scope));
+    map.clear();
+
+    // the prefix keeps policies like "main" from clashing with the functions in policy.handlebars
+    let (name, display_name) = match &body.name {
+        Some(name) => (format!("policy_{}", rust_ident(name.name)), name.name),
+        None => ("pol".to_string(), "pol"),
+    };
+    map.insert("name", name.clone());
+    // Debug formatting quotes and escapes the name, making it a Rust string literal
+    map.insert("display_name", format!("{display_name:?}"));
+    map.insert("policy", policy_logic);
+    Ok((name, render_template(handlebars, &map, POLICY_FN_TEMPLATE)))
 }
 
+// `order` is the order to compute the definitions in, by index, so each one comes after the ones it uses
 fn compile_policy<'a>(
     handlebars: &mut Handlebars,
-    policy: Policy<'a>,
-) -> Result<()> {
-    let mut env: HashMap<Variable<'a>, Marker<'a>> = HashMap::new();
-    let obligation = traverse_ast(handlebars, &policy.body, &mut env);
-    
-    let mut nodes_map: HashMap<&str, HashMap<Variable<'a>, Marker<'a>>> = HashMap::new();
-    // TODO not sure if this is going to work or if env needs to be a list
-    nodes_map.insert("bindings", env);
-    let nodes = render_template(handlebars, &nodes_map, NODES_TEMPLATE);
-
-    let mut map: HashMap<&str, &str> = HashMap::new();
-    map.insert("nodes", &nodes);
-    map.insert("obligation", &obligation);
-    let policy_logic = render_template(handlebars, &map, scope_to_template(&policy.scope));
-    map.clear();
+    policy: &'a Policy<'a>,
+    order: &[usize],
+) -> CompileResult<String> {
+    check_idents(policy)?;
+    let mut env = Env { definitions: HashSet::new(), templates: Vec::new() };
+    let mut definitions = Vec::new();
+    for definition in order.iter().map(|idx| &policy.definitions[*idx]) {
+        // templates are compiled wherever they're used
+        if !definition.parameters().is_empty() {
+            env.templates.push(definition);
+            continue;
+        }
+        let compiled = compile_definition(handlebars, definition, &mut env)
+            .map_err(|e| CompileError { file: e.file.or(definition.imported_from.map(str::to_string)), ..e })?;
+        definitions.push(compiled);
+    }
+    let definitions = definitions.join("\n");
 
-    map.insert("policy", &policy_logic);
-    let res = render_template(handlebars, &map, BASE_TEMPLATE);
+    let mut names: HashMap<String, &Variable> = HashMap::new();
+    let mut policies = Vec::new();
+    for body in &policy.bodies {
+        let (name, policy_fn) = compile_policy_body(handlebars, body, &definitions, &mut env)?;
+        // the parser rejects duplicate names, but names that differ only in punctuation make the same function
+        if let (Some(earlier), Some(this)) = (names.get(&name), &body.name) {
+            return Err(CompileError {
+                location: this.location,
+                message: format!("policy \"{}\" would have the same Rust name as policy \"{}\"; rename one of them", this.name, earlier.name),
+                file: None,
+            });
+        }
+        if let Some(this) = &body.name {
+            names.insert(name.clone(), this);
+        }
+        policies.push((name, policy_fn));
+    }
 
-    fs::write("compiled-policy.rs", &res)?;
-    Ok(())
+    let mut map: HashMap<&str, String> = HashMap::new();
+    // only policies scoped to controllers matching a pattern need the matcher
+    let matching = policy.bodies.iter().any(|body| matches!(body.scope, PolicyScope::InCtrler(ControllerSelector::Matching(_))));
+    let glob_matches = if matching { render_template(handlebars, &map, GLOB_MATCHES_TEMPLATE) } else { String::new() };
+    map.insert("glob_matches", glob_matches);
+    map.insert("policies", policies.iter().map(|(_, policy_fn)| policy_fn.as_str()).collect::<Vec<_>>().join("\n\n"));
+    map.insert("run_policies", policies.iter().map(|(name, _)| format!("{name}(ctx.clone())?;")).collect::<Vec<_>>().join("\n        "));
+    Ok(render_template(handlebars, &map, BASE_TEMPLATE))
 }
 
-pub fn compile<'a>(policy: Policy<'a>) -> Result<()> {
+// Errors are reported relative to policy_file, e.g. "policy.txt:7:5: variable \"dc\" is not bound",
+// or relative to the imported file that the offending definition came from.
+pub fn compile<'a>(policy: Policy<'a>, policy_file: &str) -> Result<()> {
     let mut handlebars = Handlebars::new();
     handlebars.register_escape_fn(no_escape);
     register_templates(&mut handlebars);
-    compile_policy(&mut handlebars, policy)
+
+    // every dependency and scoping mistake at once, rather than one per run
+    let dependencies = Dependencies::new(&policy.definitions);
+    let mut errors: Vec<String> = dependencies
+        .errors()
+        .iter()
+        .map(|e| format!("{}:{}: {e}", e.file.unwrap_or(policy_file), e.location()))
+        .collect();
+    errors.extend(resolve(&policy).iter().map(|e| format!("{}:{}: {e}", e.file.unwrap_or(policy_file), e.location())));
+    if !errors.is_empty() {
+        return Err(anyhow!("{}", errors.join("\n")));
+    }
+    let res = compile_policy(&mut handlebars, &policy, &dependencies.order())
+        .map_err(|e| anyhow!("{}:{}: {}", e.file.as_deref().unwrap_or(policy_file), e.location, e.message))?;
+
+    fs::write("compiled-policy.rs", &res)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parsers::parse;
+
+    // The Rust a policy compiles to, with each run of whitespace made a single space
+    fn compiled(policy: &str) -> String {
+        let (_, policy) = parse(policy).unwrap();
+        let mut handlebars = Handlebars::new();
+        handlebars.register_escape_fn(no_escape);
+        register_templates(&mut handlebars);
+        let order = Dependencies::new(&policy.definitions).order();
+        let compiled = compile_policy(&mut handlebars, &policy, &order).unwrap();
+        compiled.split_whitespace().collect::<Vec<_>>().join(" ")
+    }
+
+    // The mistake that keeps a policy from compiling, with where it is
+    fn compile_error(policy: &str) -> String {
+        let (_, policy) = parse(policy).unwrap();
+        let mut handlebars = Handlebars::new();
+        register_templates(&mut handlebars);
+        let order = Dependencies::new(&policy.definitions).order();
+        let e = compile_policy(&mut handlebars, &policy, &order).unwrap_err();
+        format!("{}: {}", e.location, e.message)
+    }
+
+    // A policy that checks `relation` for each "a" marked x and "b" marked y
+    fn for_each_pair(relation: &str) -> String {
+        let policy = format!("Always:\n1. For each \"a\" marked x:\n    A. For each \"b\" marked y:\n        a. {relation}");
+        compiled(&policy)
+    }
+
+    #[test]
+    pub fn test_relations() {
+        let cases = [
+            ("\"a\" goes to \"b\"", "ctx.flows_to(v_a, v_b, EdgeType::Data)"),
+            ("\"a\" does not go to \"b\"", "!ctx.flows_to(v_a, v_b, EdgeType::Data)"),
+            ("\"a\" influences \"b\"", "ctx.flows_to(v_a, v_b, EdgeType::DataAndControl)"),
+            ("\"a\" affects whether \"b\" happens", "ctx.has_ctrl_influence(v_a, v_b)"),
+            ("\"a\" does not affect whether \"b\" happens", "!ctx.has_ctrl_influence(v_a, v_b)"),
+            (
+                "\"a\" goes to the operation associated with \"b\"",
+                "ctx.all_nodes_for_ctrl(*c_id) .filter(|n| ctx.associated_call_site(*n) == ctx.associated_call_site(v_b)) .any(|n| ctx.flows_to(v_a, n, EdgeType::Data))",
+            ),
+            ("\"a\" is marked z", "ctx.has_marker(marker!(z), v_a)"),
+            ("\"a\" is not marked z", "!ctx.has_marker(marker!(z), v_a)"),
+        ];
+        for (relation, expected) in cases {
+            let compiled = for_each_pair(relation);
+            assert!(compiled.contains(&format!("all(|v_b| {{ {expected} }})")), "{relation} compiled to {compiled}");
+        }
+    }
+
+    #[test]
+    pub fn test_edge_selection() {
+        let cases = [
+            ("\"a\" goes to (data only) \"b\"", "ctx.flows_to(v_a, v_b, EdgeType::Data)"),
+            ("\"a\" goes to (via control) \"b\"", "ctx.flows_to(v_a, v_b, EdgeType::Control)"),
+            ("\"a\" goes to (data or control) \"b\"", "ctx.flows_to(v_a, v_b, EdgeType::DataAndControl)"),
+            ("\"a\" does not go to (via control) \"b\"", "!ctx.flows_to(v_a, v_b, EdgeType::Control)"),
+            ("\"a\" influences (data only) \"b\"", "ctx.flows_to(v_a, v_b, EdgeType::Data)"),
+        ];
+        for (relation, expected) in cases {
+            let compiled = for_each_pair(relation);
+            assert!(compiled.contains(&format!("all(|v_b| {{ {expected} }})")), "{relation} compiled to {compiled}");
+        }
+    }
+
+    #[test]
+    pub fn test_happens_before() {
+        let expected = |before: &str, after: &str| {
+            format!(
+                "all(|v_b| {{ (|| -> Result<bool> {{ Ok(ctx.always_happens_before( ctx.roots(*c_id, EdgeType::Data), |n| n == {before}, |n| n == {after} )?.holds()) }})().unwrap_or_else(|e| {{ ctx.error(format!(\"could not check the order of two nodes: {{e}}\")); false }}) }})"
+            )
+        };
+        assert!(for_each_pair("\"a\" happens before \"b\"").contains(&expected("v_a", "v_b")));
+        // the same check, with the variables the other way around
+        assert!(for_each_pair("\"a\" happens after \"b\"").contains(&expected("v_b", "v_a")));
+    }
+
+    #[test]
+    pub fn test_intros() {
+        let marked = "Always:\n1. For each \"a\" marked x:\n    A. There is a \"b\" type marked y where:\n        a. For each \"c\" that is a source of \"b\":\n            i) \"c\" goes to \"a\"";
+        let compiled = compiled(marked);
+        assert!(compiled.contains("ctx.all_nodes_for_ctrl(*c_id).filter(|n| ctx.has_marker(marker!(x), *n)).all(|v_a| {"));
+        assert!(compiled.contains("ctx.marked_type(marker!(y)).iter().flat_map(|t| ctx.srcs_with_type(*c_id, *t)).any(|v_b| {"));
+        assert!(compiled.contains("ctx.roots(*c_id, EdgeType::Data).filter(|n| ctx.flows_to(*n, v_b, EdgeType::Data)).all(|v_c| {"));
+    }
+
+    #[test]
+    pub fn test_filter() {
+        let policy = "Always:\n1. For each \"b\" marked y:\n    A. For each \"a\" marked x that goes to \"b\":\n        a. \"a\" is marked checked";
+        assert!(compiled(policy).contains(
+            "ctx.all_nodes_for_ctrl(*c_id).filter(|n| ctx.has_marker(marker!(x), *n)).filter(|&v_a| ctx.flows_to(v_a, v_b, EdgeType::Data)).all(|v_a| {"
+        ));
+    }
+
+    #[test]
+    pub fn test_counted() {
+        let policy = |quantifier: &str| {
+            compiled(&format!("Always:\n1. For each \"d\" marked delete:\n    A. {quantifier} \"c\" marked check where:\n        a. \"c\" goes to \"d\""))
+        };
+        let counted = |comparison: &str| {
+            format!("ctx.all_nodes_for_ctrl(*c_id).filter(|n| ctx.has_marker(marker!(check), *n)).filter(|&v_c| {{ ctx.flows_to(v_c, v_d, EdgeType::Data) }}).count() {comparison} }})")
+        };
+        assert!(policy("There is exactly one").contains(&counted("== 1")));
+        assert!(policy("There are at least two").contains(&counted(">= 2")));
+        assert!(policy("There are at most 3").contains(&counted("<= 3")));
+    }
+
+    #[test]
+    pub fn test_there_is_no() {
+        let policy = "Always:\n1. For each \"a\" marked secret:\n    A. There is no \"leak\" marked sink where:\n        a. \"a\" goes to \"leak\"";
+        assert!(compiled(policy).contains(
+            "!ctx.all_nodes_for_ctrl(*c_id).filter(|n| ctx.has_marker(marker!(sink), *n)).any(|v_leak| { ctx.flows_to(v_a, v_leak, EdgeType::Data) })"
+        ));
+    }
+
+    #[test]
+    pub fn test_marker_exprs() {
+        let or = "Always:\n1. For each \"a\" marked hash or apikey_response:\n    A. \"a\" is marked y";
+        assert!(compiled(or).contains(
+            "ctx.all_nodes_for_ctrl(*c_id).filter(|n| (ctx.has_marker(marker!(hash), *n)) || (ctx.has_marker(marker!(apikey_response), *n))).all(|v_a| {"
+        ));
+
+        let and = "Always:\n1. For each \"a\" marked sensitive and stored:\n    A. \"a\" is marked y";
+        assert!(compiled(and).contains(
+            "ctx.all_nodes_for_ctrl(*c_id).filter(|n| (ctx.has_marker(marker!(sensitive), *n)) && (ctx.has_marker(marker!(stored), *n))).all(|v_a| {"
+        ));
+
+        // a type with both markers is only counted once
+        let types = "Always:\n1. For each \"a\" type marked sensitive or stored:\n    A. \"a\" is marked y";
+        assert!(compiled(types).contains(
+            "ctx.marked_type(marker!(sensitive)).iter().chain(ctx.marked_type(marker!(stored)).iter().filter(|&t| !(ctx.marked_type(marker!(sensitive)).contains(t)))).flat_map(|t| ctx.srcs_with_type(*c_id, *t)).all(|v_a| {"
+        ));
+    }
+
+    #[test]
+    pub fn test_definitions() {
+        let policy = "Definitions:\n1. \"sink\" is each \"s\" marked sink where:\n    A. \"s\" is marked internal\n\nAlways:\n1. For each \"sink\":\n    A. \"sink\" is marked checked";
+        let compiled = compiled(policy);
+        assert!(compiled.contains(
+            "let d_sink_nodes: Vec<_> = ctx.all_nodes_for_ctrl(*c_id).filter(|n| ctx.has_marker(marker!(sink), *n)).filter(|&v_s| { ctx.has_marker(marker!(internal), v_s) }).collect();"
+        ));
+        assert!(compiled.contains("d_sink_nodes.iter().copied().all(|v_sink| { ctx.has_marker(marker!(checked), v_sink) })"));
+    }
+
+    #[test]
+    pub fn test_only_via() {
+        let policy = "Always:\n1. Each input goes to a \"b\" marked y only via a \"c\" marked z";
+        assert!(compiled(policy).contains(
+            "let is_compliant = ctx.always_happens_before( ctx.roots(*c_id, EdgeType::Data), |checkpoint| ctx.all_nodes_for_ctrl(*c_id).filter(|n| ctx.has_marker(marker!(z), *n)).any(|n| n == checkpoint), |terminal| ctx.all_nodes_for_ctrl(*c_id).filter(|n| ctx.has_marker(marker!(y), *n)).any(|n| n == terminal) )?.holds();"
+        ));
+    }
+
+    #[test]
+    pub fn test_scopes() {
+        let body = "1. For each \"a\" marked x:\n    A. \"a\" is marked y";
+        let always = compiled(&format!("Always:\n{body}"));
+        assert!(always.contains("for c_id in ctx.desc().controllers.keys() { let is_compliant = "));
+        assert!(always.contains("assert_error!(ctx, is_compliant, format!(\"Controller {} is not compliant with the policy\", c_id));"));
+        assert!(always.contains("policy!(pol, \"pol\", ctx {"));
+        assert!(always.contains("pol(ctx.clone())?;"));
+
+        let sometimes = compiled(&format!("Sometimes:\n{body}"));
+        assert!(sometimes.contains("let mut success : bool = false; for c_id in ctx.desc().controllers.keys() {"));
+        assert!(sometimes.contains("if is_compliant { success = true; break; }"));
+    }
+
+    #[test]
+    pub fn test_controller_scopes() {
+        let body = "1. For each \"a\" marked x:\n    A. \"a\" is marked y";
+        let named = compiled(&format!("In gdpr_deletes, account_purge:\n{body}"));
+        assert!(named.contains(
+            "for name in [\"gdpr_deletes\", \"account_purge\"] { assert_error!(ctx, ctx.desc().controllers.values().any(|ctrl| ctrl.name.as_str() == name), format!(\"Could not find a controller named {name}.\")); }"
+        ));
+        assert!(named.contains(
+            "for (c_id, ctrl) in ctx.desc().controllers.iter().filter(|(_, ctrl)| [\"gdpr_deletes\", \"account_purge\"].contains(&ctrl.name.as_str())) {"
+        ));
+        assert!(named.contains("assert_error!(ctx, found_ctrler, format!(\"Could not find a controller {}.\", \"named gdpr_deletes, account_purge\"));"));
+        assert!(!named.contains("fn glob_matches"));
+
+        let except = compiled(&format!("In all controllers except gdpr_deletes:\n{body}"));
+        assert!(except.contains(".filter(|(_, ctrl)| ![\"gdpr_deletes\"].contains(&ctrl.name.as_str())) {"));
+
+        // the matcher is only emitted for the policies that need it
+        let matching = compiled(&format!("In controllers matching \"*_deletes\":\n{body}"));
+        assert!(matching.contains(".filter(|(_, ctrl)| glob_matches(\"*_deletes\", ctrl.name.as_str())) {"));
+        assert!(matching.contains("fn glob_matches(pattern: &str, name: &str) -> bool {"));
+        assert!(!matching.contains("Could not find a controller named"));
+    }
+
+    #[test]
+    pub fn test_named_policies() {
+        let policy = "Policy \"community deletion\":\nAlways:\n1. For each \"a\" marked x:\n    A. \"a\" is marked y\n\nPolicy \"instance ban\":\nSometimes:\n1. For each \"b\" marked x:\n    A. \"b\" is marked z";
+        let compiled = compiled(policy);
+        assert!(compiled.contains("policy!(policy_community_deletion, \"community deletion\", ctx {"));
+        assert!(compiled.contains("policy!(policy_instance_ban, \"instance ban\", ctx { let mut success : bool = false;"));
+        assert!(compiled.contains("policy_community_deletion(ctx.clone())?; policy_instance_ban(ctx.clone())?;"));
+    }
+
+    #[test]
+    pub fn test_variable_idents() {
+        // names the templates use themselves, or that are Rust keywords, can't be taken by policy variables
+        let policy = "Always:\n1. For each \"n\" marked x:\n    A. For each \"type\" marked y:\n        a. \"n\" goes to \"type\"";
+        let generated = compiled(policy);
+        assert!(generated.contains(".all(|v_n| {"));
+        assert!(generated.contains("ctx.flows_to(v_n, v_type, EdgeType::Data)"));
+
+        let spelled_out = "Always:\n1. For each \"user's email\" marked x:\n    A. For each \"données\" marked y:\n        a. \"user's email\" goes to \"données\"";
+        assert!(compiled(spelled_out).contains("ctx.flows_to(v_user_s_email, v_donn_ue9_es, EdgeType::Data)"));
+
+        // a definition's nodes can't collide with a variable named like them
+        let nodes = "Definitions:\n1. \"sink\" is each \"s\" marked sink where:\n    A. \"s\" is marked internal\n\nAlways:\n1. For each \"sink nodes\" marked x:\n    A. For each \"sink\":\n        a. \"sink nodes\" goes to \"sink\"";
+        let compiled = compiled(nodes);
+        assert!(compiled.contains("let d_sink_nodes: Vec<_>"));
+        assert!(compiled.contains("ctx.flows_to(v_sink_nodes, v_sink, EdgeType::Data)"));
+    }
+
+    #[test]
+    pub fn test_otherwise() {
+        let policy = "Always:\n1. For each \"a\" marked x:\n    A. If \"a\" is marked y then:\n        a. \"a\" is marked z\n    Otherwise:\n        a. \"a\" is marked w";
+        assert!(compiled(policy).contains(
+            "if ctx.has_marker(marker!(y), v_a) { ctx.has_marker(marker!(z), v_a) } else { ctx.has_marker(marker!(w), v_a) }"
+        ));
+    }
+
+    #[test]
+    pub fn test_quantified_condition() {
+        // the body is checked for each node that satisfies the condition, so it can use "c"
+        let there_is = "Always:\n1. For each \"sink\" marked sink:\n    A. If there is a \"c\" marked check where \"c\" goes to \"sink\" then:\n        a. \"c\" is marked x";
+        assert!(compiled(there_is).contains(
+            "ctx.all_nodes_for_ctrl(*c_id).filter(|n| ctx.has_marker(marker!(check), *n)).all(|v_c| { if ctx.flows_to(v_c, v_sink, EdgeType::Data) { ctx.has_marker(marker!(x), v_c) } else { true } })"
+        ));
+
+        let for_each = "Always:\n1. For each \"sink\" marked sink:\n    A. If for each \"c\" marked check, \"c\" goes to \"sink\" then:\n        a. \"sink\" is marked x";
+        assert!(compiled(for_each).contains(
+            "if ctx.all_nodes_for_ctrl(*c_id).filter(|n| ctx.has_marker(marker!(check), *n)).all(|v_c| { ctx.flows_to(v_c, v_sink, EdgeType::Data) }) { ctx.has_marker(marker!(x), v_sink) } else { true }"
+        ));
+    }
+
+    #[test]
+    pub fn test_unless() {
+        let policy = "Always:\n1. For each \"a\" marked x:\n    A. Unless \"a\" is marked safe:\n        a. \"a\" is marked checked";
+        assert!(compiled(policy).contains("if ctx.has_marker(marker!(safe), v_a) { true } else { ctx.has_marker(marker!(checked), v_a) }"));
+
+        // the body holds when the condition doesn't, and the Otherwise block when it does
+        let otherwise = "Always:\n1. For each \"a\" marked x:\n    A. Unless \"a\" is marked safe:\n        a. \"a\" is marked checked\n    Otherwise:\n        a. \"a\" is marked trusted";
+        assert!(compiled(otherwise).contains(
+            "if ctx.has_marker(marker!(safe), v_a) { ctx.has_marker(marker!(trusted), v_a) } else { ctx.has_marker(marker!(checked), v_a) }"
+        ));
+    }
+
+    #[test]
+    pub fn test_ident_collisions() {
+        let policy = "Always:\n1. For each \"user's email\" marked x:\n    A. For each \"user s email\" marked y:\n        a. \"user's email\" goes to \"user s email\"";
+        assert_eq!(
+            compile_error(policy),
+            "3:18: variable \"user s email\" would have the same Rust name as variable \"user's email\" at 2:14; rename one of them"
+        );
+
+        // definitions are checked against the variables in the bodies
+        let definition = "Definitions:\n1. \"a-b\" is each \"s\" marked sink where:\n    A. \"s\" is marked internal\n\nAlways:\n1. For each \"a b\" marked x:\n    A. \"a b\" is marked y";
+        assert_eq!(
+            compile_error(definition),
+            "6:14: variable \"a b\" would have the same Rust name as variable \"a-b\" at 2:5; rename one of them"
+        );
+
+        // a spelled-out character can collide too
+        let spelled_out = "Always:\n1. For each \"é\" marked x:\n    A. For each \"_ue9_\" marked y:\n        a. \"é\" goes to \"_ue9_\"";
+        assert_eq!(
+            compile_error(spelled_out),
+            "3:18: variable \"_ue9_\" would have the same Rust name as variable \"é\" at 2:14; rename one of them"
+        );
+
+        // the same name used again is the same variable
+        let reused = "Always:\n1. For each \"é\" marked x:\n    A. \"é\" is marked y\nand\n2. For each \"é\" marked z:\n    A. \"é\" is marked w";
+        assert!(compiled(reused).contains("ctx.has_marker(marker!(w), v__ue9_)"));
+    }
 }
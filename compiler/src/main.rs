@@ -1,29 +1,45 @@
 use std::env;
-use std::fs;
 
-use parsers::parse;
-use std::io::Result;
+use anyhow::Result;
+use compile::compile;
+use parsers::{dependencies::Dependencies, lint::mixed_indentation, loader::{LoadError, Sources}, report::render_warning, DEFAULT_CONFIG};
+
+mod compile;
 
 fn run(args: &Vec<String>) -> Result<()> {
     if args.len() < 2 {
         panic!("Need to pass path to policy file");
     }
     let policy_file = &args[1];
-    let policy = fs::read_to_string(policy_file)
-        .expect("Could not read policy file");
+    // the policy file and any definitions it imports
+    let sources = Sources::load(policy_file, &DEFAULT_CONFIG)?;
+
+    for file in &sources.files {
+        for warning in mixed_indentation(&file.source, DEFAULT_CONFIG.tab_width()) {
+            eprintln!("{}\n", render_warning(&warning, &file.source, &file.path));
+        }
+    }
 
-    let res = parse(&policy);
-    dbg!(&res);
-    // match res {
-    //     Ok((_, ast)) => compile(ast),
-    //     Err(e) => panic!("{}", e),
-    // }
-    todo!()
+    let policy = sources.parse()?;
+    let policy_source = sources.files.last().expect("the policy file is always loaded");
+    for (file, warning) in Dependencies::new(&policy.definitions).warnings() {
+        // imported definitions are reported in the file they came from
+        let source = file.and_then(|file| sources.files.iter().find(|source| source.path == file)).unwrap_or(policy_source);
+        eprintln!("{}\n", render_warning(&warning, &source.source, &source.path));
+    }
+    compile(policy, policy_file)
 }
 
 fn main() -> Result<()> {
     let args: Vec<String> = env::args().collect();
-    run(&args)?;
+    if let Err(err) = run(&args) {
+        // parse reports already start with `error:`, so print them as they are rather than behind anyhow's `Error: `
+        if let Some(LoadError::Parse(report)) = err.downcast_ref::<LoadError>() {
+            eprintln!("{report}");
+            std::process::exit(1);
+        }
+        return Err(err);
+    }
     // Command::new("rustfmt compiled-policy.rs").output().expect("failed to run cargo fmt");
     Ok(())
 }
@@ -2,179 +2,465 @@ use nom::{
     branch::alt,
     bytes::complete::tag,
     error::context,
-    sequence::{tuple, delimited, preceded, pair}, character::complete::space1, multi::many0, combinator::map,
+    sequence::{tuple, preceded, pair, terminated}, character::complete::{digit1, space0, space1}, combinator::{cut, map, map_res, opt, value},
 };
 
 use crate::{
-    ASTNode, Res, common::*, relations::*,
-    variable_intro::variable_intro, Clause, ClauseIntro,
+    ASTNode, Res, Span, common::*, relations::*,
+    variable_intro::variable_intro, Clause, ClauseIntro, Count, VariableIntro,
 };
 
-fn l4_clause<'a>(s: &'a str) -> Res<&str, ASTNode<'a>> {
-    let mut combinator = context(
-        "l4 clause",
-        tuple((
-            preceded(l4_bullet, alt((for_each, there_is, conditional))),
-            l5_relations,
-        ))
+fn clause<'a>(level: usize, s: Span<'a>) -> Res<Span<'a>, (Bullet<'a>, ASTNode<'a>)> {
+    let mut head = context(
+        "clause",
+        pair(
+            |s| bullet(level, s),
+            |s| if level == 1 {
+                // there's nothing for a top-level conditional to refer to
+                alt((for_each, counted, there_is, there_is_no))(s)
+            } else {
+                // "There is at least" starts like "There is a", so counted must come first
+                alt((for_each, counted, there_is, there_is_no, conditional, unless))(s)
+            },
+        )
     );
-    let (remainder, (intro, body)) = combinator(s)?;
+    let (remainder, (parent, intro)) = head(s)?;
+    // a bullet and an intro can only be a clause, so the body must follow
+    let mut nested = preceded(
+        |s| check_nested(parent, level + 1, s),
+        cut(|s| clauses(level + 1, s))
+    );
+    let (remainder, body) = nested(remainder)?;
+    // `Otherwise:` belongs to the clause whose bullet it lines up with.
+    // One indented less belongs to an enclosing clause, so it's left for that clause to parse.
+    let (after, keyword) = opt(preceded(multispace_comment0, tag("Otherwise")))(remainder)?;
+    let (remainder, otherwise) = match keyword.map(|keyword| (keyword, indentation(&keyword))) {
+        None => (remainder, None),
+        Some((_, Some(width))) if parent.indentation.is_none_or(|parent| width < parent) => (remainder, None),
+        Some((keyword, width)) if width != parent.indentation => return Err(failure(keyword, "misaligned otherwise")),
+        Some((keyword, _)) => match intro {
+            ClauseIntro::Conditional(_) | ClauseIntro::Unless(_) => {
+                let (remainder, otherwise) = preceded(cut(colon), &mut nested)(after)?;
+                (remainder, Some(otherwise))
+            },
+            _ => return Err(failure(keyword, "misplaced otherwise")),
+        },
+    };
     Ok((
         remainder,
-        ASTNode::Clause(Box::new(Clause {
+        (parent, ASTNode::Clause(Box::new(Clause {
             intro,
-            body
-        }))
+            body,
+            otherwise,
+            location: parent.label.into()
+        })))
     ))
 }
 
-pub fn l4_clauses<'a>(s: &'a str) -> Res<&str, ASTNode<'a>> { 
+// Top-level items are clauses or only via relations; nested items are clauses or relations.
+fn item<'a>(level: usize, s: Span<'a>) -> Res<Span<'a>, (Bullet<'a>, ASTNode<'a>)> {
+    if level == 1 {
+        alt((|s| clause(level, s), bulleted_only_via_relation))(s)
+    } else {
+        alt((|s| clause(level, s), |s| bulleted_relation(level, s)))(s)
+    }
+}
+
+// Items at the given level (starting at 1) in any order, joined by operators.
+pub fn clauses<'a>(level: usize, s: Span<'a>) -> Res<Span<'a>, ASTNode<'a>> {
     context(
-        "multiple l4 clauses",
-        map(
-            pair(
-                l4_clause, 
-                many0(pair(operator, alt((l4_clause, l4_relations))))
-            ),
-            join_nodes
-        )
+        "clauses",
+        bulleted_items(move |s| item(level, s))
     )(s)
 }
 
-fn l3_clause<'a>(s: &'a str) -> Res<&str, ASTNode<'a>> {
+// there is a "c" marked consent_check where "c" goes to "sink"
+// A filter can stand in for the where-condition: there is a "c" marked consent_check that goes to "sink"
+// The where-condition runs up to `then`, so it includes any relations joined to it.
+fn there_is_condition<'a>(s: Span<'a>) -> Res<Span<'a>, ASTNode<'a>> {
     let mut combinator = context(
-        "l3 clause",
-        tuple((
-            preceded(l3_bullet, alt((for_each, there_is, conditional))),
-            alt((
-                l4_relations,
-                l4_clauses
-            ))
-        ))
+        "there is condition",
+        preceded(
+            tuple((tag("there is a"), space1)),
+            cut(pair(filtered_variable_intro, opt(preceded(tuple((tag("where"), space1)), condition))))
+        )
     );
-    let (remainder, (intro, body)) = combinator(s)?;
-    Ok((
-        remainder,
-        ASTNode::Clause(Box::new(Clause {
-            intro,
-            body
-        }))
-    ))
+    let (remainder, ((intro, filter), where_condition)) = combinator(s)?;
+    let (filter, body) = match (filter, where_condition) {
+        (filter, Some(where_condition)) => (filter, where_condition),
+        (Some(filter), None) => (None, filter),
+        (None, None) => return Err(failure(remainder, "missing where condition")),
+    };
+    let clause = Clause { intro: ClauseIntro::ThereIs((intro, filter)), body, otherwise: None, location: s.into() };
+    Ok((remainder, ASTNode::Clause(Box::new(clause))))
 }
 
-pub fn l3_clauses<'a>(s: &'a str) -> Res<&str, ASTNode<'a>> { 
-    context(
-        "multiple l3 clauses",
-        map(
-            pair(
-                l3_clause, 
-                many0(pair(operator, alt((l3_clause, l3_relations))))
-            ),
-            join_nodes
+// for each "c" marked consent_check, "c" goes to "sink"
+fn for_each_condition<'a>(s: Span<'a>) -> Res<Span<'a>, ASTNode<'a>> {
+    let mut combinator = context(
+        "for each condition",
+        preceded(
+            tuple((tag("for each"), space1)),
+            cut(pair(filtered_variable_intro, preceded(context("comma", pair(tag(","), space0)), condition)))
         )
-    )(s)
+    );
+    let (remainder, ((intro, filter), body)) = combinator(s)?;
+    let clause = Clause { intro: ClauseIntro::ForEach((intro, filter)), body, otherwise: None, location: s.into() };
+    Ok((remainder, ASTNode::Clause(Box::new(clause))))
+}
+
+fn condition_item<'a>(s: Span<'a>) -> Res<Span<'a>, ASTNode<'a>> {
+    preceded(space0, alt((there_is_condition, for_each_condition, map(relation, ASTNode::Relation))))(s)
 }
 
-fn l2_clause<'a>(s: &'a str) -> Res<&str, ASTNode<'a>> {
+// Relations and quantified conditions, joined by `and` or `or`,
+// e.g. "a" goes to "b" and "b" is not marked internal
+fn condition<'a>(s: Span<'a>) -> Res<Span<'a>, ASTNode<'a>> {
+    context("condition", joined(condition_item, condition_item))(s)
+}
+
+fn conditional<'a>(s: Span<'a>) -> Res<Span<'a>, ClauseIntro<'a>> {
     let mut combinator = context(
-        "l2 clause",
-        tuple((
-            preceded(l2_bullet, alt((for_each, there_is, conditional))),
-            alt((
-                l3_relations,
-                l3_clauses,
-                // join nodes of pair(alt((relation, clause, many0(pair(operator, alt((l3_clause, l3_relations))))
-            ))
-        ))
+        "conditional",
+        preceded(
+            tag("If"),
+            // once we've seen "If", this must be a conditional, so don't backtrack
+            cut(terminated(condition, context("then", tuple((tag("then"), colon)))))
+        )
     );
-    let (remainder, (intro, body)) = combinator(s)?;
-    Ok((
-        remainder,
-        ASTNode::Clause(Box::new(Clause {
-            intro,
-            body
-        }))
-    ))
+    let (remainder, condition) = combinator(s)?;
+    Ok((remainder, ClauseIntro::Conditional(condition)))
 }
 
-pub fn l2_clauses<'a>(s: &'a str) -> Res<&str, ASTNode<'a>> { 
-    context(
-        "multiple l2 clauses",
-        map(
-            pair(
-                l2_clause, 
-                many0(pair(operator, alt((l2_clause, l2_relations))))
-            ),
-            join_nodes
+fn unless<'a>(s: Span<'a>) -> Res<Span<'a>, ClauseIntro<'a>> {
+    let mut combinator = context(
+        "unless",
+        preceded(
+            tag("Unless"),
+            cut(terminated(condition, colon))
         )
-    )(s)
+    );
+    let (remainder, condition) = combinator(s)?;
+    Ok((remainder, ClauseIntro::Unless(condition)))
 }
 
-fn l1_clause<'a>(s: &'a str) -> Res<&str, ASTNode<'a>> {
+// A variable intro, optionally followed by a filter on the introduced variable.
+// Roots have no variable for a filter to talk about.
+fn filtered_variable_intro<'a>(s: Span<'a>) -> Res<Span<'a>, (VariableIntro<'a>, Option<ASTNode<'a>>)> {
+    let (remainder, var_intro) = variable_intro(s)?;
+    match var_intro.variable() {
+        Some(subject) => {
+            let (remainder, filter) = opt(|s| filter(subject, s))(remainder)?;
+            Ok((remainder, (var_intro, filter)))
+        },
+        None => Ok((remainder, (var_intro, None))),
+    }
+}
+
+fn for_each<'a>(s: Span<'a>) ->  Res<Span<'a>, ClauseIntro<'a>> {
     let mut combinator = context(
-        "l1 clause",
-        tuple((
-            preceded(l1_bullet, alt((for_each, there_is))),
-            alt((
-                l2_relations,
-                l2_clauses,
-            )),
-        ))
+        "for each",
+        preceded(
+            tuple((tag("For each"), space1)),
+            cut(terminated(filtered_variable_intro, colon))
+        )
     );
-    let (remainder, (intro, body)) = combinator(s)?;
-    Ok((
-        remainder,
-        ASTNode::Clause(Box::new(Clause {
-            intro,
-            body
-        }))
-    ))
+    let (remainder, intro) = combinator(s)?;
+    Ok((remainder, ClauseIntro::ForEach(intro)))
 }
 
-pub fn l1_clauses<'a>(s: &'a str) -> Res<&str, ASTNode<'a>> { 
-    context(
-        "multiple l1 clauses",
-        map(
-            pair(
-                alt((l1_clause, map(only_via_relation, |rel| ASTNode::Relation(rel)))),
-                many0(tuple((operator, alt((l1_clause, map(only_via_relation, |rel| ASTNode::Relation(rel)))))))
-            ),
-            join_nodes
+fn there_is<'a>(s: Span<'a>) ->  Res<Span<'a>, ClauseIntro<'a>> {
+    let mut combinator = context(
+        "there is",
+        preceded(
+            tag("There is a"), 
+            cut(terminated(filtered_variable_intro, context("where", tag("where:"))))
         )
+    );
+    let (remainder, intro) = combinator(s)?;
+    Ok((remainder, ClauseIntro::ThereIs(intro)))
+}
+
+// 2, or a number word like "one"
+fn number<'a>(s: Span<'a>) -> Res<Span<'a>, usize> {
+    context(
+        "number",
+        alt((
+            map_res(digit1, |digits: Span<'a>| digits.fragment().parse()),
+            value(1, tag("one")),
+            value(2, tag("two")),
+            value(3, tag("three")),
+            value(4, tag("four")),
+            value(5, tag("five")),
+            value(6, tag("six")),
+            value(7, tag("seven")),
+            value(8, tag("eight")),
+            value(9, tag("nine")),
+            value(10, tag("ten")),
+        ))
     )(s)
 }
 
-fn conditional<'a>(s: &'a str) -> Res<&str, ClauseIntro<'a>> {
-    let mut combinator = context(
-        "conditional",
-        delimited(tag("If"), relation, tuple((tag("then"), colon)))
+// at least 2, at most one, exactly one
+fn count<'a>(s: Span<'a>) -> Res<Span<'a>, Count> {
+    let mut combinator = pair(
+        alt((tag("at least"), tag("at most"), tag("exactly"))),
+        preceded(space1, cut(number))
     );
-    let (remainder, relation) = combinator(s)?;
-    Ok((remainder, ClauseIntro::Conditional(relation)))
+    let (remainder, (quantifier, n)) = combinator(s)?;
+    let count = match *quantifier.fragment() {
+        "at least" => Count::AtLeast(n),
+        "at most" => Count::AtMost(n),
+        _ => Count::Exactly(n),
+    };
+    Ok((remainder, count))
 }
 
-fn for_each<'a>(s: &'a str) ->  Res<&str, ClauseIntro<'a>> {
+fn counted<'a>(s: Span<'a>) ->  Res<Span<'a>, ClauseIntro<'a>> {
     let mut combinator = context(
-        "for each",
-        delimited(
-            tuple((tag("For each"), space1)),
-            variable_intro,
-            colon
+        "counted",
+        preceded(
+            tuple((tag("There"), space1, alt((tag("is"), tag("are"))), space1)),
+            pair(
+                count,
+                cut(terminated(filtered_variable_intro, context("where", tag("where:"))))
+            )
         )
     );
-    let (remainder, var_intro) = combinator(s)?;
-    Ok((remainder, ClauseIntro::ForEach(var_intro)))
+    let (remainder, (count, (intro, filter))) = combinator(s)?;
+    Ok((remainder, ClauseIntro::Counted((count, intro, filter))))
 }
 
-fn there_is<'a>(s: &'a str) ->  Res<&str, ClauseIntro<'a>> {
+fn there_is_no<'a>(s: Span<'a>) ->  Res<Span<'a>, ClauseIntro<'a>> {
     let mut combinator = context(
-        "there is",
-        delimited(
-            tag("There is a"), 
-            variable_intro,
-            tag("where:")
+        "there is no",
+        preceded(
+            tag("There is no"),
+            cut(terminated(filtered_variable_intro, context("where", tag("where:"))))
         )
     );
-    let (remainder, var_intro) = combinator(s)?;
-    Ok((remainder, ClauseIntro::ThereIs(var_intro)))
+    let (remainder, intro) = combinator(s)?;
+    Ok((remainder, ClauseIntro::ThereIsNo(intro)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse, Location, Relation};
+
+    // The clause a bullet introduces
+    fn clause_of<'n, 'a>(node: &'n ASTNode<'a>) -> &'n Clause<'a> {
+        match node {
+            ASTNode::Clause(clause) => clause,
+            node => panic!("expected a clause, got {node:?}"),
+        }
+    }
+
+    // The lines of the clauses that have an Otherwise block
+    fn otherwise_lines(policy: &str) -> Vec<u32> {
+        fn visit(node: &ASTNode, lines: &mut Vec<u32>) {
+            match node {
+                ASTNode::Relation(_) => (),
+                ASTNode::And(obligation) | ASTNode::Or(obligation) | ASTNode::Conditional(obligation) => {
+                    visit(&obligation.src, lines);
+                    visit(&obligation.dest, lines);
+                },
+                ASTNode::Clause(clause) => {
+                    if clause.otherwise.is_some() {
+                        lines.push(clause.location.line);
+                    }
+                    visit(&clause.body, lines);
+                    clause.otherwise.iter().for_each(|otherwise| visit(otherwise, lines));
+                },
+            }
+        }
+        let (_, policy) = parse(policy).unwrap();
+        let mut lines = vec![];
+        visit(&policy.bodies[0].body, &mut lines);
+        lines
+    }
+
+    #[test]
+    pub fn test_otherwise_after_nested_body() {
+        let for_each = "Always:\n1. For each \"a\" marked x:\n    A. If \"a\" is marked y then:\n        a. For each \"b\" marked z:\n            i) \"a\" goes to \"b\"\n    Otherwise:\n        a. \"a\" is marked w";
+        assert_eq!(otherwise_lines(for_each), vec![3]);
+
+        let there_is = "Always:\n1. For each \"a\" marked x:\n    A. Unless \"a\" is marked y:\n        a. There is a \"b\" marked z where:\n            i) \"a\" goes to \"b\"\n    Otherwise:\n        a. \"a\" is marked w";
+        assert_eq!(otherwise_lines(there_is), vec![3]);
+
+        // each Otherwise goes with the If it lines up with
+        let nested = "Always:\n1. For each \"a\" marked x:\n    A. If \"a\" is marked y then:\n        a. If \"a\" is marked z then:\n            i) \"a\" is marked v\n        Otherwise:\n            i) \"a\" is marked w\n    Otherwise:\n        a. \"a\" is marked u";
+        assert_eq!(otherwise_lines(nested), vec![3, 4]);
+
+        let outer = "Always:\n1. For each \"a\" marked x:\n    A. If \"a\" is marked y then:\n        a. If \"a\" is marked z then:\n            i) \"a\" is marked v\n    Otherwise:\n        a. \"a\" is marked u";
+        assert_eq!(otherwise_lines(outer), vec![3]);
+    }
+
+    #[test]
+    pub fn test_misaligned_otherwise() {
+        let policy = "Always:\n1. For each \"a\" marked x:\n    A. If \"a\" is marked y then:\n        a. \"a\" is marked z\n        Otherwise:\n        a. \"a\" is marked w";
+        assert_eq!(parse_failure(policy), Some(("misaligned otherwise", Location { line: 5, column: 9 })));
+
+        let between = "Always:\n1. For each \"a\" marked x:\n    A. If \"a\" is marked y then:\n        a. \"a\" is marked z\n      Otherwise:\n        a. \"a\" is marked w";
+        assert_eq!(parse_failure(between), Some(("misaligned otherwise", Location { line: 5, column: 7 })));
+    }
+
+    #[test]
+    pub fn test_misplaced_otherwise() {
+        let policy = "Always:\n1. For each \"a\" marked x:\n    A. For each \"b\" marked y:\n        a. \"a\" goes to \"b\"\n    Otherwise:\n        a. \"a\" is marked w";
+        assert_eq!(parse_failure(policy), Some(("misplaced otherwise", Location { line: 5, column: 5 })));
+    }
+
+    #[test]
+    pub fn test_unless() {
+        let policy = "Always:\n1. For each \"a\" marked x:\n    A. Unless \"a\" is marked safe or \"a\" is marked internal:\n        a. \"a\" is marked checked";
+        let (_, parsed) = parse(policy).unwrap();
+        let ASTNode::Clause(for_each) = &parsed.bodies[0].body else {
+            panic!("expected a clause, got {:?}", parsed.bodies[0].body);
+        };
+        let ASTNode::Clause(unless) = &for_each.body else {
+            panic!("expected a clause, got {:?}", for_each.body);
+        };
+        assert!(matches!(&unless.intro, ClauseIntro::Unless(ASTNode::Or(_))));
+        assert_eq!(unless.otherwise, None);
+
+        // there's nothing for a top-level condition to refer to
+        let top_level = "Always:\n1. Unless \"a\" is marked safe:\n    A. \"a\" is marked checked";
+        assert!(parse(top_level).is_err());
+
+        let missing_colon = "Always:\n1. For each \"a\" marked x:\n    A. Unless \"a\" is marked safe\n        a. \"a\" is marked checked";
+        assert!(parse(missing_colon).is_err());
+    }
+
+    #[test]
+    pub fn test_filter() {
+        let policy = "Always:\n1. For each \"data\" marked community_data that goes to \"write\" and is not marked safe:\n    A. \"data\" is marked checked";
+        let (_, parsed) = parse(policy).unwrap();
+        let ClauseIntro::ForEach((intro, Some(ASTNode::And(filter)))) = &clause_of(&parsed.bodies[0].body).intro else {
+            panic!("expected a filtered For each, got {:?}", parsed.bodies[0].body);
+        };
+        assert_eq!(intro.variable().map(|var| var.name), Some("data"));
+        // the introduced variable is the subject of each predicate
+        assert!(matches!(&filter.src, ASTNode::Relation(Relation::FlowsTo((subject, dest, _))) if subject.name == "data" && dest.name == "write"));
+        assert!(matches!(&filter.dest, ASTNode::Relation(Relation::IsNotMarked((subject, marker))) if subject.name == "data" && marker.name == "safe"));
+
+        let there_is = "Always:\n1. For each \"a\" marked x:\n    A. There is a \"b\" marked y that goes to \"a\" where:\n        a. \"b\" is marked z";
+        let (_, parsed) = parse(there_is).unwrap();
+        let for_each = clause_of(&parsed.bodies[0].body);
+        assert!(matches!(&clause_of(&for_each.body).intro, ClauseIntro::ThereIs((_, Some(ASTNode::Relation(Relation::FlowsTo(_)))))));
+
+        // "that" must be followed by what the variable does
+        let dangling = "Always:\n1. For each \"a\" marked x that:\n    A. \"a\" is marked y";
+        assert!(parse(dangling).is_err());
+
+        // roots aren't a variable a filter could talk about
+        let roots = "Always:\n1. For each input that goes to \"b\":\n    A. \"b\" is marked y";
+        assert!(parse(roots).is_err());
+    }
+
+    #[test]
+    pub fn test_any_order() {
+        let policy = "Always:\n1. For each \"a\" marked x:\n    A. \"a\" is marked y\n    and\n    B. For each \"b\" marked z:\n        a. \"a\" goes to \"b\"\n    and\n    C. \"a\" is marked w";
+        let (_, parsed) = parse(policy).unwrap();
+        // joined from the left: (A and B) and C
+        let ASTNode::And(outer) = &clause_of(&parsed.bodies[0].body).body else {
+            panic!("expected items joined by and, got {:?}", parsed.bodies[0].body);
+        };
+        let ASTNode::And(inner) = &outer.src else {
+            panic!("expected items joined by and, got {:?}", outer.src);
+        };
+        assert!(matches!(&inner.src, ASTNode::Relation(Relation::IsMarked(_))));
+        assert!(matches!(&inner.dest, ASTNode::Clause(_)));
+        assert!(matches!(&outer.dest, ASTNode::Relation(Relation::IsMarked(_))));
+
+        // items still need an operator between them
+        let missing_operator = "Always:\n1. For each \"a\" marked x:\n    A. \"a\" is marked y\n    B. For each \"b\" marked z:\n        a. \"a\" goes to \"b\"";
+        assert!(parse(missing_operator).is_err());
+
+        // a relation at the top level has no variables to talk about
+        let top_level = "Always:\n1. For each \"a\" marked x:\n    A. \"a\" is marked y\nand\n2. \"a\" is marked z";
+        assert!(parse(top_level).is_err());
+    }
+
+    #[test]
+    pub fn test_there_is_no() {
+        let policy = "Always:\n1. For each \"secret\" marked secret:\n    A. There is no \"leak\" marked sink where:\n        a. \"secret\" goes to \"leak\"";
+        let (_, parsed) = parse(policy).unwrap();
+        let there_is_no = clause_of(&clause_of(&parsed.bodies[0].body).body);
+        assert!(matches!(&there_is_no.intro, ClauseIntro::ThereIsNo((VariableIntro::VariableMarked((var, _)), None)) if var.name == "leak"));
+        assert!(matches!(&there_is_no.body, ASTNode::Relation(Relation::FlowsTo(_))));
+
+        let missing_where = "Always:\n1. For each \"secret\" marked secret:\n    A. There is no \"leak\" marked sink:\n        a. \"secret\" goes to \"leak\"";
+        assert!(parse(missing_where).is_err());
+    }
+
+    #[test]
+    pub fn test_counted() {
+        let count = |quantifier: &str| {
+            let policy = format!("Always:\n1. For each \"d\" marked delete:\n    A. {quantifier} \"c\" marked check where:\n        a. \"c\" goes to \"d\"");
+            let (_, parsed) = parse(&policy).unwrap_or_else(|e| panic!("couldn't parse {quantifier}: {e:?}"));
+            match &clause_of(&clause_of(&parsed.bodies[0].body).body).intro {
+                ClauseIntro::Counted((count, VariableIntro::VariableMarked((var, _)), None)) if var.name == "c" => *count,
+                intro => panic!("expected a counted intro, got {intro:?}"),
+            }
+        };
+        assert_eq!(count("There is exactly one"), Count::Exactly(1));
+        assert_eq!(count("There are at least 12"), Count::AtLeast(12));
+        assert_eq!(count("There are at most ten"), Count::AtMost(10));
+        // "is" and "are" aren't checked against the number
+        assert_eq!(count("There is at least three"), Count::AtLeast(3));
+    }
+
+    #[test]
+    pub fn test_malformed_counted() {
+        let policy = |quantifier: &str| format!("Always:\n1. For each \"d\" marked delete:\n    A. {quantifier} \"c\" marked check where:\n        a. \"c\" goes to \"d\"");
+        assert!(parse(&policy("There are at least many")).is_err());
+        assert!(parse(&policy("There are at least eleven")).is_err());
+        assert!(parse(&policy("There are exactly")).is_err());
+        assert!(parse(&policy("There are about 2")).is_err());
+
+        let missing_where = "Always:\n1. There is exactly one \"c\" marked check:\n    A. \"c\" is marked y";
+        assert!(parse(missing_where).is_err());
+    }
+
+    #[test]
+    pub fn test_quantified_condition() {
+        // The condition of the clause under the top-level For each
+        fn condition<'a>(parsed: &'a crate::Policy<'a>) -> &'a ASTNode<'a> {
+            match &clause_of(&clause_of(&parsed.bodies[0].body).body).intro {
+                ClauseIntro::Conditional(condition) | ClauseIntro::Unless(condition) => condition,
+                intro => panic!("expected a condition, got {intro:?}"),
+            }
+        }
+
+        let there_is = "Always:\n1. For each \"sink\" marked sink:\n    A. If there is a \"c\" marked check where \"c\" goes to \"sink\" and \"c\" is marked strong then:\n        a. \"c\" is marked x";
+        let (_, parsed) = parse(there_is).unwrap();
+        let ASTNode::Clause(clause) = condition(&parsed) else {
+            panic!("expected a quantified condition, got {:?}", condition(&parsed));
+        };
+        assert!(matches!(&clause.intro, ClauseIntro::ThereIs((_, None))));
+        // the where-condition runs up to `then`
+        assert!(matches!(&clause.body, ASTNode::And(_)));
+
+        // a filter can stand in for the where-condition
+        let filtered = "Always:\n1. For each \"sink\" marked sink:\n    A. Unless there is a \"c\" marked check that goes to \"sink\":\n        a. \"sink\" is marked x";
+        let (_, parsed) = parse(filtered).unwrap();
+        assert!(matches!(condition(&parsed), ASTNode::Clause(clause) if matches!(clause.body, ASTNode::Relation(Relation::FlowsTo(_)))));
+
+        let for_each = "Always:\n1. For each \"sink\" marked sink:\n    A. If \"sink\" is marked safe or for each \"c\" marked check, \"c\" goes to \"sink\" then:\n        a. \"sink\" is marked x";
+        let (_, parsed) = parse(for_each).unwrap();
+        let ASTNode::Or(or) = condition(&parsed) else {
+            panic!("expected an or, got {:?}", condition(&parsed));
+        };
+        assert!(matches!(&or.dest, ASTNode::Clause(clause) if matches!(clause.intro, ClauseIntro::ForEach(_))));
+    }
+
+    #[test]
+    pub fn test_malformed_quantified_condition() {
+        let missing_where = "Always:\n1. For each \"sink\" marked sink:\n    A. If there is a \"c\" marked check then:\n        a. \"c\" is marked x";
+        assert_eq!(parse_failure(missing_where), Some(("missing where condition", Location { line: 3, column: 39 })));
+
+        let missing_comma = "Always:\n1. For each \"sink\" marked sink:\n    A. If for each \"c\" marked check \"c\" goes to \"sink\" then:\n        a. \"sink\" is marked x";
+        assert!(parse(missing_comma).is_err());
+    }
 }
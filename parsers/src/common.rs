@@ -1,145 +1,217 @@
 use nom::{
     branch::alt,
-    bytes::complete::{tag, take_while1},
-    character::complete::{alpha1, space0, space1, multispace0, multispace1, digit1},
-    combinator::{opt, recognize},
-    error::context,
-    multi::many1,
-    sequence::{delimited, terminated, tuple, preceded},
+    bytes::complete::{tag, take, take_while, take_while1},
+    character::complete::{satisfy, space0, space1, multispace1, not_line_ending},
+    combinator::{map, peek, recognize, verify},
+    error::{context, VerboseError, VerboseErrorKind},
+    multi::{many0, many1},
+    sequence::{delimited, terminated, tuple, preceded, pair},
+    Parser,
 };
 
 use crate::{
-    Marker, Operator, Variable, Res, ASTNode, TwoNodeObligation
+    Marker, MarkerExpr, Operator, Variable, Res, ASTNode, TwoNodeObligation, Span, BulletKind, BulletDelimiter
 };
 
-pub fn colon(s: &str) -> Res<&str, &str> {
-    context("colon", delimited(space0, tag(":"), multispace0))(s)
+// A line comment, which runs to the end of the line
+fn comment(s: Span) -> Res<Span, Span> {
+    context("comment", recognize(pair(alt((tag("#"), tag("//"))), not_line_ending)))(s)
 }
 
-pub fn and(s: &str) -> Res<&str, &str> {
-    context("and", delimited(multispace0, tag("and"), multispace1))(s)
+// Whitespace, including newlines and comments
+pub fn multispace_comment0(s: Span) -> Res<Span, Span> {
+    recognize(many0(alt((multispace1, comment))))(s)
 }
 
-pub fn or(s: &str) -> Res<&str, &str> {
-    context("or", delimited(multispace0, tag("or"), multispace1))(s)
+pub fn multispace_comment1(s: Span) -> Res<Span, Span> {
+    recognize(many1(alt((multispace1, comment))))(s)
 }
 
-pub fn operator<'a>(s: &'a str) -> Res<&str, Operator> {
+pub fn colon(s: Span) -> Res<Span, Span> {
+    context("colon", delimited(space0, tag(":"), multispace_comment0))(s)
+}
+
+pub fn and(s: Span) -> Res<Span, Span> {
+    context("and", delimited(multispace_comment0, tag("and"), multispace_comment1))(s)
+}
+
+pub fn or(s: Span) -> Res<Span, Span> {
+    context("or", delimited(multispace_comment0, tag("or"), multispace_comment1))(s)
+}
+
+// The operator, along with where it was written
+pub fn operator(s: Span) -> Res<Span, (Span, Operator)> {
     let mut combinator = context("operator", alt((and, or)));
     let (remainder, operator_str) = combinator(s)?;
-    Ok((remainder, operator_str.into()))
+    Ok((remainder, (operator_str, (*operator_str.fragment()).into())))
 }
 
-pub fn l1_bullet(s: &str) -> Res<&str, &str> {
-    context(
-        "l1 bullet",
-        delimited(
-            multispace0,
-            digit1, 
-            tuple((tag("."), space1))
-        )
-    )(s)
+// An operator between two words on the same line, e.g. in `marked hash or apikey_response`
+fn inline_operator(s: Span) -> Res<Span, (Span, Operator)> {
+    let mut combinator = delimited(space1, alt((tag("and"), tag("or"))), space1);
+    let (remainder, operator_str) = combinator(s)?;
+    Ok((remainder, (operator_str, (*operator_str.fragment()).into())))
 }
 
-pub fn l2_bullet(s: &str) -> Res<&str, &str> {
-    let uppercase = "ABCDEFGHIJKLMNOPQRSTUVWXYZ";
-    context(
-        "l2 bullet",
-        delimited(
-            multispace0,
-            take_while1(|c| uppercase.contains(c)),
-            tuple((tag("."), space1))
-        )
-    )(s)
+#[derive(Debug, Clone, Copy)]
+pub struct Bullet<'a> {
+    // where the bullet starts, including an opening parenthesis
+    pub start: Span<'a>,
+    pub label: Span<'a>,
+    // position in its list, e.g. "iv" is 4
+    pub ordinal: usize,
+    // width of the whitespace before the bullet, or None if the bullet doesn't start its line
+    pub indentation: Option<usize>,
 }
 
-pub fn l3_bullet(s: &str) -> Res<&str, &str> {
-    let lowercase = "abcdefghijklmnopqrstuvwxyz";
-    context(
-        "l3 bullet",
-        delimited(
-            multispace0,
-            take_while1(|c| lowercase.contains(c)),
-            tuple((tag("."), space1))
-        )
-    )(s)
+// Tabs advance to the next tab stop
+fn indentation_width(whitespace: &str, tab_width: usize) -> usize {
+    whitespace.chars().fold(0, |width, c| match c {
+        '\t' => (width / tab_width + 1) * tab_width,
+        _ => width + 1,
+    })
 }
 
-pub fn l4_bullet(s: &str) -> Res<&str, &str> {
-    // todo: this is lazy, write a real roman numeral parser later
-    let roman = "ixv";
-    context(
-        "l4 bullet",
-        delimited(
-            multispace0,
-            take_while1(|c| roman.contains(c)),
-            // terminate with ) to avoid ambiguity with l3 bullet lowercase i
-            tuple((tag(")"), space1))
-        )
-    )(s)
+// Width of the whitespace before `start`, or None if something else comes before it on its line
+pub fn indentation(start: &Span) -> Option<usize> {
+    let line = std::str::from_utf8(start.get_line_beginning()).ok()?;
+    let before = &line[..start.get_column() - 1];
+    before
+        .chars()
+        .all(|c| c == ' ' || c == '\t')
+        .then(|| indentation_width(before, start.extra.tab_width()))
 }
 
-pub fn l5_bullet(s: &str) -> Res<&str, &str> {
-    let uppercase = "ABCDEFGHIJKLMNOPQRSTUVWXYZ";
-    context(
-        "l5 bullet",
-        delimited(
-            multispace0,
-            take_while1(|c| uppercase.contains(c)),
-            tuple((tag(")"), space1))
+// Parse the bullet of an item at the given nesting level (starting at 1),
+// in whichever style the config assigns to that level.
+pub fn bullet(level: usize, s: Span) -> Res<Span, Bullet> {
+    let style = s.extra.bullet_style(level);
+    let is_label: fn(char) -> bool = match style.kind {
+        BulletKind::Numeric => |c| c.is_ascii_digit(),
+        BulletKind::UpperAlpha => |c| c.is_ascii_uppercase(),
+        BulletKind::LowerAlpha => |c| c.is_ascii_lowercase(),
+        BulletKind::LowerRoman => |c| "ivxlcdm".contains(c),
+        BulletKind::UpperRoman => |c| "IVXLCDM".contains(c),
+    };
+    let (open, close) = match style.delimiter {
+        BulletDelimiter::Period => ("", "."),
+        BulletDelimiter::Paren => ("", ")"),
+        BulletDelimiter::Parens => ("(", ")"),
+    };
+    let (remainder, (start, label)) = context(
+        "bullet",
+        preceded(
+            multispace_comment0,
+            pair(
+                peek(take(0usize)),
+                delimited(tag(open), take_while1(is_label), tuple((tag(close), space1)))
+            )
         )
-    )(s)
+    )(s)?;
+    // this is definitely a bullet at this level, just a malformed one like "iiii)"
+    match style.kind.ordinal(label.fragment()) {
+        Some(ordinal) => Ok((remainder, Bullet { start, label, ordinal, indentation: indentation(&start) })),
+        None => Err(failure(label, "bullet label")),
+    }
 }
 
-pub fn alphabetic_with_underscores<'a>(s: &'a str) -> Res<&str, Marker<'a>> {
-    context(
-        "alphabetic with underscores",
-        preceded(
-            space0,
-            recognize(
-                many1(
-                    tuple((alpha1, opt(tag("_"))))
-                )
-            ),
-        )
+fn rust_identifier(s: Span) -> Res<Span, Span> {
+    verify(
+        recognize(
+            pair(
+                satisfy(|c| c.is_ascii_alphabetic() || c == '_'),
+                take_while(|c: char| c.is_ascii_alphanumeric() || c == '_')
+            )
+        ),
+        |name: &Span| *name.fragment() != "_"
     )(s)
 }
 
-pub fn marker<'a>(s: &'a str) -> Res<&str, Marker<'a>> {
+// Markers and controllers are named in Rust source, e.g. #[paralegal::marker(sha256_hash)],
+// so their names follow Rust's identifier grammar. A lone underscore is not an identifier.
+pub fn identifier(s: Span) -> Res<Span, Span> {
+    context("identifier", preceded(space0, rust_identifier))(s)
+}
+
+// A parameter of a definition template, like <m>, which stands in for a marker until the definition is used.
+// It keeps its angle brackets, so it can't be mistaken for a marker of the same name.
+pub fn parameter(s: Span) -> Res<Span, Span> {
+    context("parameter", preceded(space0, recognize(tuple((tag("<"), rust_identifier, tag(">"))))))(s)
+}
+
+// The name inside a variable's quotes can be anything that fits on one line, like "user's email".
+// Surrounding whitespace would make "data" and " data" different variables, so it isn't allowed.
+pub fn quoted_name(s: Span) -> Res<Span, Span> {
     context(
-        "marker",
-        terminated(
-            alphabetic_with_underscores,
-            multispace0
+        "quoted name",
+        verify(
+            take_while1(|c| c != '"' && c != '\n' && c != '\r'),
+            |name: &Span| name.trim() == *name.fragment()
         )
     )(s)
 }
 
-pub fn variable<'a>(s: &'a str) -> Res<&str, Variable<'a>> {
-    context(
+fn marker_name<'a>(s: Span<'a>) -> Res<Span<'a>, Marker<'a>> {
+    let mut combinator = context("marker", alt((identifier, parameter)));
+    let (remainder, name) = combinator(s)?;
+    Ok((
+        remainder,
+        Marker {
+            name: name.fragment(),
+            location: name.into()
+        }
+    ))
+}
+
+pub fn marker<'a>(s: Span<'a>) -> Res<Span<'a>, Marker<'a>> {
+    terminated(marker_name, multispace_comment0)(s)
+}
+
+// One marker, or several joined by `and` or `or` on one line, e.g. `hash or apikey_response`
+pub fn marker_expr<'a>(s: Span<'a>) -> Res<Span<'a>, MarkerExpr<'a>> {
+    let mut combinator = context(
+        "marker expression",
+        terminated(operands(inline_operator, marker_name, marker_name), multispace_comment0)
+    );
+    let (remainder, (head, tail)) = combinator(s)?;
+    let expr = match tail.first() {
+        None => MarkerExpr::Marker(head),
+        Some(((_, op), _)) => {
+            let markers = std::iter::once(head).chain(tail.iter().map(|(_, marker)| *marker)).collect();
+            match op {
+                Operator::And => MarkerExpr::And(markers),
+                Operator::Or => MarkerExpr::Or(markers),
+            }
+        },
+    };
+    Ok((remainder, expr))
+}
+
+pub fn variable<'a>(s: Span<'a>) -> Res<Span<'a>, Variable<'a>> {
+    let mut combinator = context(
         "variable",
-        alt((
-            delimited(
-                tuple((space0, tag("\""))),
-                alphabetic_with_underscores,
-                tuple((tag("\""), space0)), 
-            ),
-            delimited(
-                tuple((space0, tag("\""))),
-                recognize(many1(tuple((alpha1, opt(space1))))),
-                tuple((tag("\""), space0)), 
-            )
-        ))   
-    )(s)
+        delimited(
+            tuple((space0, tag("\""))),
+            quoted_name,
+            tuple((tag("\""), space0)), 
+        )
+    );
+    let (remainder, name) = combinator(s)?;
+    Ok((
+        remainder,
+        Variable {
+            name: name.fragment(),
+            location: name.into()
+        }
+    ))
 }
 
 // Given an initial node and a vector of (operator, node) pairs, construct an ASTNode::{Operator}
 // joining each of the nodes
-// TODO add assertion that each operator in the vector is the same (can't mix ands & ors)
-pub fn join_nodes<'a>(tup: (ASTNode<'a>, Vec<(Operator, ASTNode<'a>)>)) -> ASTNode<'a> {
-    tup.1
+fn join_nodes<'a>(first: ASTNode<'a>, rest: Vec<((Span<'a>, Operator), ASTNode<'a>)>) -> ASTNode<'a> {
+    rest
     .into_iter()
-    .fold(tup.0, |acc, (op, clause)| {
+    .fold(first, |acc, ((_, op), clause)| {
         let ob = TwoNodeObligation {
             src: acc,
             dest: clause
@@ -150,3 +222,190 @@ pub fn join_nodes<'a>(tup: (ASTNode<'a>, Vec<(Operator, ASTNode<'a>)>)) -> ASTNo
         }
     })
 }
+
+// The first operand, then each following operand with the operator before it
+type Operands<'a, T> = (T, Vec<((Span<'a>, Operator), T)>);
+
+// Parse `first`, then any number of `rest` preceded by operators.
+// "A and B or C" could be read as either (A and B) or C or A and (B or C),
+// so all of the operators at one level must be the same.
+fn operands<'a, T, O, F, G>(mut operator: O, mut first: F, mut rest: G) -> impl FnMut(Span<'a>) -> Res<Span<'a>, Operands<'a, T>>
+where
+    O: Parser<Span<'a>, (Span<'a>, Operator), VerboseError<Span<'a>>>,
+    F: Parser<Span<'a>, T, VerboseError<Span<'a>>>,
+    G: Parser<Span<'a>, T, VerboseError<Span<'a>>>,
+{
+    move |s| {
+        let (remainder, head) = first.parse(s)?;
+        let (remainder, tail) = many0(pair(|s| operator.parse(s), |s| rest.parse(s)))(remainder)?;
+        if let Some(((_, expected), _)) = tail.first() {
+            if let Some(((mixed, _), _)) = tail.iter().find(|((_, op), _)| op != expected) {
+                return Err(failure(*mixed, "mixed operators"));
+            }
+        }
+        Ok((remainder, (head, tail)))
+    }
+}
+
+// Parse `first`, then any number of `rest` preceded by operators, and join them.
+pub fn joined<'a, F, G>(first: F, rest: G) -> impl FnMut(Span<'a>) -> Res<Span<'a>, ASTNode<'a>>
+where
+    F: Parser<Span<'a>, ASTNode<'a>, VerboseError<Span<'a>>>,
+    G: Parser<Span<'a>, ASTNode<'a>, VerboseError<Span<'a>>>,
+{
+    map(operands(operator, first, rest), |(head, tail)| join_nodes(head, tail))
+}
+
+pub fn failure<'a>(span: Span<'a>, context: &'static str) -> nom::Err<VerboseError<Span<'a>>> {
+    nom::Err::Failure(VerboseError {
+        errors: vec![(span, VerboseErrorKind::Context(context))]
+    })
+}
+
+// Bullets in a list must count up from the first one in their style (1., 2., 3. or i), ii), iii)).
+// A skipped or repeated bullet usually means an item was accidentally deleted or pasted twice.
+// They must also line up, since a bullet indented like some other list is probably in the wrong place.
+pub fn check_list<'a>(bullets: impl IntoIterator<Item = Bullet<'a>>) -> Result<(), nom::Err<VerboseError<Span<'a>>>> {
+    let mut indentation = None;
+    for (expected, bullet) in bullets.into_iter().enumerate() {
+        if bullet.ordinal != expected + 1 {
+            return Err(failure(bullet.label, "bullet out of sequence"));
+        }
+        match (indentation, bullet.indentation) {
+            (Some(first), Some(this)) if first != this => return Err(failure(bullet.start, "misaligned bullet")),
+            (None, this) => indentation = this,
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+// The first bullet of a nested list must be indented further than the bullet it's nested under;
+// check_list lines the rest of the list up with it.
+pub fn check_nested<'a>(parent: Bullet<'a>, level: usize, s: Span<'a>) -> Res<Span<'a>, ()> {
+    if let Ok((_, child)) = bullet(level, s) {
+        if let (Some(parent_indentation), Some(child_indentation)) = (parent.indentation, child.indentation) {
+            if child_indentation <= parent_indentation {
+                return Err(failure(child.start, "bullet not indented"));
+            }
+        }
+    }
+    Ok((s, ()))
+}
+
+// Bulleted items joined by operators; `item` returns each item's bullet along with the item.
+pub fn bulleted_items<'a, F>(item: F) -> impl FnMut(Span<'a>) -> Res<Span<'a>, ASTNode<'a>>
+where
+    F: Parser<Span<'a>, (Bullet<'a>, ASTNode<'a>), VerboseError<Span<'a>>> + Copy,
+{
+    move |s| {
+        let (remainder, ((first_bullet, head), tail)) = operands(operator, item, item)(s)?;
+        check_list(std::iter::once(first_bullet).chain(tail.iter().map(|(_, (bullet, _))| *bullet)))?;
+        let tail = tail.into_iter().map(|(op, (_, node))| (op, node)).collect();
+        Ok((remainder, join_nodes(head, tail)))
+    }
+}
+
+// The mistake the parser recognized in `policy` and where it is, if it failed on one
+#[cfg(test)]
+pub fn parse_failure(policy: &str) -> Option<(&'static str, crate::Location)> {
+    match crate::parse(policy) {
+        Err(nom::Err::Failure(e)) => e.errors.iter().find_map(|(span, kind)| match kind {
+            VerboseErrorKind::Context(context) => Some((*context, (*span).into())),
+            _ => None,
+        }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse, Location};
+
+    fn problem(policy: &str) -> Option<&'static str> {
+        parse_failure(policy).map(|(problem, _)| problem)
+    }
+
+    #[test]
+    pub fn test_list_in_sequence() {
+        let policy = "Always:\n1. For each \"a\" marked x:\n    A. \"a\" is marked y\nand\n2. For each \"b\" marked x:\n    A. \"b\" is marked y";
+        assert!(parse(policy).is_ok());
+
+        let nested = "Always:\n1. For each \"a\" marked x:\n    A. \"a\" is marked y\n    and\n    B. \"a\" is marked z";
+        assert!(parse(nested).is_ok());
+    }
+
+    #[test]
+    pub fn test_skipped_bullet() {
+        let policy = "Always:\n1. For each \"a\" marked x:\n    A. \"a\" is marked y\nand\n3. For each \"b\" marked x:\n    A. \"b\" is marked y";
+        assert_eq!(problem(policy), Some("bullet out of sequence"));
+
+        let nested = "Always:\n1. For each \"a\" marked x:\n    A. \"a\" is marked y\n    and\n    C. \"a\" is marked z";
+        assert_eq!(problem(nested), Some("bullet out of sequence"));
+    }
+
+    #[test]
+    pub fn test_duplicated_bullet() {
+        let policy = "Always:\n1. For each \"a\" marked x:\n    A. \"a\" is marked y\nand\n1. For each \"b\" marked x:\n    A. \"b\" is marked y";
+        assert_eq!(problem(policy), Some("bullet out of sequence"));
+    }
+
+    #[test]
+    pub fn test_list_starts_at_one() {
+        let policy = "Always:\n2. For each \"a\" marked x:\n    A. \"a\" is marked y";
+        assert_eq!(problem(policy), Some("bullet out of sequence"));
+    }
+
+    #[test]
+    pub fn test_misaligned_bullet() {
+        let policy = "Always:\n1. For each \"a\" marked x:\n    A. \"a\" is marked y\n      and\n      B. \"a\" is marked z";
+        assert_eq!(problem(policy), Some("misaligned bullet"));
+    }
+
+    #[test]
+    pub fn test_malformed_label() {
+        let policy = "Always:\n1. For each \"a\" marked x:\n    A. For each \"b\" marked y:\n        a. For each \"c\" marked z:\n            iiii) \"c\" is marked w";
+        assert_eq!(problem(policy), Some("bullet label"));
+    }
+
+    #[test]
+    pub fn test_bullet_not_indented() {
+        let policy = "Always:\n1. For each \"a\" marked x:\n    A. For each \"b\" marked y:\n    a. \"a\" goes to \"b\"";
+        assert_eq!(parse_failure(policy), Some(("bullet not indented", Location { line: 4, column: 5 })));
+
+        let outdented = "Always:\n  1. For each \"a\" marked x:\n A. \"a\" is marked y";
+        assert_eq!(parse_failure(outdented), Some(("bullet not indented", Location { line: 3, column: 2 })));
+    }
+
+    #[test]
+    pub fn test_mixed_operators() {
+        let policy = "Always:\n1. For each \"a\" marked x:\n    A. \"a\" is marked y\n    and\n    B. \"a\" is marked z\n    or\n    C. \"a\" is marked w";
+        assert_eq!(parse_failure(policy), Some(("mixed operators", Location { line: 6, column: 5 })));
+
+        let inline = "Always:\n1. For each \"a\" marked x:\n    A. If \"a\" is marked y and \"a\" is marked z or \"a\" is marked w then:\n        a. \"a\" is marked v";
+        assert_eq!(parse_failure(inline), Some(("mixed operators", Location { line: 3, column: 47 })));
+
+        let same = "Always:\n1. For each \"a\" marked x:\n    A. \"a\" is marked y\n    or\n    B. \"a\" is marked z\n    or\n    C. \"a\" is marked w";
+        assert!(parse(same).is_ok());
+    }
+
+    #[test]
+    pub fn test_comments() {
+        let policy = "# reviewed in #412\nAlways:\n// every a is checked\n1. For each \"a\" marked x: # inline\n    A. \"a\" is marked y // trailing\n    # between items\n    and\n    B. \"a\" is marked z\n# at the end";
+        let (_, parsed) = parse(policy).unwrap();
+        assert!(matches!(&parsed.bodies[0].body, ASTNode::Clause(clause) if matches!(clause.body, ASTNode::And(_))));
+        // the same policy with the comments cut out, which leaves everything else where it was
+        let uncommented: Vec<&str> = policy.lines().map(|line| line.split(['#', '/']).next().unwrap()).collect();
+        let uncommented = uncommented.join("\n");
+        assert_eq!(parsed, parse(&uncommented).unwrap().1);
+
+        // a single slash doesn't start a comment
+        let slash = "Always:\n/ not a comment\n1. For each \"a\" marked x:\n    A. \"a\" is marked y";
+        assert!(parse(slash).is_err());
+
+        // a comment runs to the end of the line, so it can't come between words
+        let inside = "Always:\n1. For each # note\n \"a\" marked x:\n    A. \"a\" is marked y";
+        assert!(parse(inside).is_err());
+    }
+}
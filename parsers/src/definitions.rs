@@ -1,44 +1,52 @@
 use nom::{
     bytes::complete::tag,
-    character::complete::{space1, multispace0},
+    character::complete::space1,
     error::context,
     multi::many1,
-    sequence::{preceded, tuple}, branch::alt,
+    sequence::{preceded, tuple}, combinator::cut,
 };
 
 use crate::{
-    Definition, Res, common::*, 
-    variable_intro::variable_intro, clause::l2_clauses, relations::l2_relations,
+    Definition, Res, Span, common::*, 
+    variable_intro::variable_intro, clause::clauses,
 };
 
-fn definition<'a>(s: &'a str) -> Res<&str, Definition<'a>> {
-    let mut combinator = context(
+fn definition<'a>(s: Span<'a>) -> Res<Span<'a>, (Bullet<'a>, Definition<'a>)> {
+    let mut head = context(
         "definition",
         tuple((
-            preceded(l1_bullet, variable),
-            preceded(tuple((tag("is each"), space1)), variable_intro),
-            preceded(tuple((tag("where"), colon)), alt((l2_relations, l2_clauses)))
+            |s| bullet(1, s),
+            variable,
+            preceded(cut(context("is each", tuple((tag("is each"), space1)))), cut(variable_intro)),
+            cut(context("where", tuple((tag("where"), colon)))),
         ))
     );
-
-    let (remainder, (variable, declaration, filter)) = combinator(s)?;
+    let (remainder, (parent, variable, declaration, _)) = head(s)?;
+    let (remainder, filter) = context(
+        "definition",
+        preceded(|s| check_nested(parent, 2, s), cut(|s| clauses(2, s)))
+    )(remainder)?;
     
     Ok((
         remainder,
-        Definition { 
+        (parent, Definition { 
             variable,
             declaration,
-            filter
-        }
+            filter,
+            imported_from: None,
+        })
     ))
 }
 
-pub fn parse_definitions<'a>(s: &'a str) -> Res<&str, Vec<Definition<'a>>> {
-    context(
+pub fn parse_definitions<'a>(s: Span<'a>) -> Res<Span<'a>, Vec<Definition<'a>>> {
+    let mut combinator = context(
         "definitions",
         preceded(
-            tuple((multispace0, tag("Definitions"), colon)),
+            tuple((multispace_comment0, tag("Definitions"), colon)),
             many1(definition)
         )
-    )(s)
+    );
+    let (remainder, definitions) = combinator(s)?;
+    check_list(definitions.iter().map(|(bullet, _)| *bullet))?;
+    Ok((remainder, definitions.into_iter().map(|(_, definition)| definition).collect()))
 }
\ No newline at end of file
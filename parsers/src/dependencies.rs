@@ -0,0 +1,305 @@
+use std::fmt::Display;
+
+use crate::{lint::Warning, ASTNode, ClauseIntro, Definition, Location, Relation, Variable, VariableIntro};
+
+// Definitions can quantify over other definitions, as in `There is a "sensitive sink" where:`,
+// so each definition depends on the ones it introduces.
+// A definition has to be computed after everything it depends on, which rules out cycles.
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Problem<'a> {
+    // the use that closes the cycle, and the definitions in it, starting and ending with the same one
+    Cycle((Variable<'a>, Vec<&'a str>)),
+    // introduced as a definition's name, but there is no such definition
+    Undefined(Variable<'a>),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct DependencyError<'a> {
+    pub problem: Problem<'a>,
+    // the imported file the problem is in; None for the policy file itself
+    pub file: Option<&'a str>,
+}
+
+impl<'a> DependencyError<'a> {
+    pub fn location(&self) -> Location {
+        match &self.problem {
+            Problem::Cycle((var, _)) | Problem::Undefined(var) => var.location,
+        }
+    }
+}
+
+impl<'a> Display for DependencyError<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.problem {
+            Problem::Cycle((_, cycle)) => {
+                let cycle: Vec<String> = cycle.iter().map(|name| format!("\"{name}\"")).collect();
+                write!(f, "definition {} depends on itself: {}", cycle[0], cycle.join(" -> "))
+            },
+            Problem::Undefined(var) => {
+                write!(f, "variable \"{}\" needs a marker; there is no definition for it", var.name)
+            },
+        }
+    }
+}
+
+// Every variable intro in a node, including the ones in filters and `only via` relations
+fn intros<'n, 'a>(node: &'n ASTNode<'a>, found: &mut Vec<&'n VariableIntro<'a>>) {
+    match node {
+        ASTNode::Relation(Relation::OnlyVia(intros)) => {
+            let (src, dest, checkpoint) = &**intros;
+            found.extend([src, dest, checkpoint]);
+        },
+        ASTNode::Relation(_) => (),
+        ASTNode::And(obligation) | ASTNode::Or(obligation) | ASTNode::Conditional(obligation) => {
+            intros(&obligation.src, found);
+            intros(&obligation.dest, found);
+        },
+        ASTNode::Clause(clause) => {
+            match &clause.intro {
+                ClauseIntro::ForEach((intro, filter))
+                | ClauseIntro::ThereIs((intro, filter))
+                | ClauseIntro::ThereIsNo((intro, filter))
+                | ClauseIntro::Counted((_, intro, filter)) => {
+                    found.push(intro);
+                    if let Some(filter) = filter {
+                        intros(filter, found);
+                    }
+                },
+                ClauseIntro::Conditional(condition) | ClauseIntro::Unless(condition) => intros(condition, found),
+            }
+            intros(&clause.body, found);
+            if let Some(otherwise) = &clause.otherwise {
+                intros(otherwise, found);
+            }
+        },
+    }
+}
+
+// The definition a name refers to, either by name or as a use of a template
+fn lookup(definitions: &[Definition], name: &str) -> Option<usize> {
+    definitions
+        .iter()
+        .position(|definition| definition.variable.name == name && definition.parameters().is_empty())
+        .or_else(|| definitions.iter().position(|template| template.instantiate(name).is_some()))
+}
+
+pub struct Dependencies<'p, 'a> {
+    definitions: &'p [Definition<'a>],
+    // for each definition, the definitions it uses, by index, along with where it uses them
+    uses: Vec<Vec<(usize, Variable<'a>)>>,
+    // names that aren't any definition
+    undefined: Vec<(usize, Variable<'a>)>,
+}
+
+impl<'p, 'a> Dependencies<'p, 'a> {
+    pub fn new(definitions: &'p [Definition<'a>]) -> Self {
+        let mut uses = vec![];
+        let mut undefined = vec![];
+        for (idx, definition) in definitions.iter().enumerate() {
+            let mut found = vec![&definition.declaration];
+            intros(&definition.filter, &mut found);
+            let mut used = vec![];
+            for intro in found {
+                let VariableIntro::Variable(var) = intro else {
+                    continue;
+                };
+                match lookup(definitions, var.name) {
+                    Some(dependency) => used.push((dependency, *var)),
+                    None => undefined.push((idx, *var)),
+                }
+            }
+            uses.push(used);
+        }
+        Dependencies { definitions, uses, undefined }
+    }
+
+    // Cycles, each reported once, and names that aren't any definition
+    pub fn errors(&self) -> Vec<DependencyError<'a>> {
+        let mut errors: Vec<DependencyError<'a>> = self
+            .undefined
+            .iter()
+            .map(|(idx, var)| DependencyError { problem: Problem::Undefined(*var), file: self.definitions[*idx].imported_from })
+            .collect();
+        let mut done = vec![false; self.definitions.len()];
+        for idx in 0..self.definitions.len() {
+            self.find_cycles(idx, &mut vec![], &mut done, &mut errors);
+        }
+        errors
+    }
+
+    // Depth first; `stack` holds the definitions currently being visited
+    fn find_cycles(&self, idx: usize, stack: &mut Vec<usize>, done: &mut [bool], errors: &mut Vec<DependencyError<'a>>) {
+        if done[idx] {
+            return;
+        }
+        stack.push(idx);
+        for (dependency, var) in &self.uses[idx] {
+            if let Some(start) = stack.iter().position(|on_stack| on_stack == dependency) {
+                let mut cycle: Vec<&'a str> = stack[start..].iter().map(|idx| self.definitions[*idx].variable.name).collect();
+                cycle.push(self.definitions[*dependency].variable.name);
+                let file = self.definitions[idx].imported_from;
+                errors.push(DependencyError { problem: Problem::Cycle((*var, cycle)), file });
+            } else {
+                self.find_cycles(*dependency, stack, done, errors);
+            }
+        }
+        stack.pop();
+        done[idx] = true;
+    }
+
+    // Uses of a definition that's written further down, with the file each one is in.
+    // They're fine, since definitions are computed in `order`, but they make a policy harder to read.
+    pub fn warnings(&self) -> Vec<(Option<&'a str>, Warning)> {
+        let mut warnings = vec![];
+        for (idx, used) in self.uses.iter().enumerate() {
+            for (dependency, var) in used.iter().filter(|(dependency, _)| *dependency > idx) {
+                let definition = &self.definitions[*dependency].variable;
+                warnings.push((self.definitions[idx].imported_from, Warning {
+                    location: var.location,
+                    message: format!("\"{}\" is used before its definition at {}", definition.name, definition.location),
+                }));
+            }
+        }
+        warnings
+    }
+
+    // The definitions, by index, with each one after the definitions it uses.
+    // Otherwise they keep the order they're written in. Assumes there are no cycles.
+    pub fn order(&self) -> Vec<usize> {
+        let mut order = vec![];
+        let mut done = vec![false; self.definitions.len()];
+        for idx in 0..self.definitions.len() {
+            self.visit(idx, &mut done, &mut order);
+        }
+        order
+    }
+
+    fn visit(&self, idx: usize, done: &mut [bool], order: &mut Vec<usize>) {
+        if done[idx] {
+            return;
+        }
+        done[idx] = true;
+        for (dependency, _) in &self.uses[idx] {
+            self.visit(*dependency, done, order);
+        }
+        order.push(idx);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+
+    // The definitions' names in the order they're computed
+    fn order(policy: &str) -> Vec<&str> {
+        let (_, policy) = parse(policy).unwrap();
+        let dependencies = Dependencies::new(&policy.definitions);
+        assert!(dependencies.errors().is_empty());
+        dependencies.order().into_iter().map(|idx| policy.definitions[idx].variable.name).collect()
+    }
+
+    #[test]
+    pub fn test_self_cycle() {
+        let policy = r#"Definitions:
+1. "a" is each "x" marked m where:
+    A. There is a "a" where:
+        a. "x" goes to "a"
+
+Always:
+1. For each "u" marked user:
+    A. "u" is marked z"#;
+        let (_, policy) = parse(policy).unwrap();
+        let errors = Dependencies::new(&policy.definitions).errors();
+        assert!(matches!(errors.as_slice(), [DependencyError { problem: Problem::Cycle((var, cycle)), file: None }]
+            if var.location == Location { line: 3, column: 20 } && cycle == &["a", "a"]));
+    }
+
+    #[test]
+    pub fn test_cycle() {
+        let policy = r#"Definitions:
+1. "a" is each "x" marked m where:
+    A. There is a "b" where:
+        a. "x" goes to "b"
+2. "b" is each "y" marked m where:
+    A. There is a "a" where:
+        a. "y" goes to "a"
+3. "c" is each "z" marked m where:
+    A. There is a "a" where:
+        a. "z" goes to "a"
+
+Always:
+1. For each "u" marked user:
+    A. "u" is marked z"#;
+        let (_, policy) = parse(policy).unwrap();
+        let errors = Dependencies::new(&policy.definitions).errors();
+        // reported once, from where it's first reached
+        assert!(matches!(errors.as_slice(), [DependencyError { problem: Problem::Cycle((var, cycle)), .. }]
+            if var.name == "a" && var.location.line == 6 && cycle == &["a", "b", "a"]));
+    }
+
+    #[test]
+    pub fn test_diamond() {
+        let policy = r#"Definitions:
+1. "top" is each "t" marked m where:
+    A. There is a "left" where:
+        a. "t" goes to "left"
+    and
+    B. There is a "right" where:
+        a. "t" goes to "right"
+2. "left" is each "l" marked m where:
+    A. There is a "bottom" where:
+        a. "l" goes to "bottom"
+3. "right" is each "r" marked m where:
+    A. There is a "bottom" where:
+        a. "r" goes to "bottom"
+4. "bottom" is each "b" marked m where:
+    A. "b" is marked n
+
+Always:
+1. For each "u" marked user:
+    A. "u" is marked z"#;
+        assert_eq!(order(policy), vec!["bottom", "left", "right", "top"]);
+    }
+
+    #[test]
+    pub fn test_undefined() {
+        let policy = r#"Definitions:
+1. "a" is each "x" marked m where:
+    A. There is a "b" where:
+        a. "x" goes to "b"
+
+Always:
+1. For each "u" marked user:
+    A. "u" is marked z"#;
+        let (_, policy) = parse(policy).unwrap();
+        let errors = Dependencies::new(&policy.definitions).errors();
+        assert!(matches!(errors.as_slice(), [DependencyError { problem: Problem::Undefined(var), .. }]
+            if var.name == "b" && var.location.line == 3));
+        assert_eq!(errors[0].to_string(), "variable \"b\" needs a marker; there is no definition for it");
+    }
+
+    #[test]
+    pub fn test_used_before_definition() {
+        let policy = r#"Definitions:
+1. "a" is each "x" marked m where:
+    A. There is a "stored sensitive" where:
+        a. "x" goes to "stored sensitive"
+2. "stored <m>" is each "y" marked <m> where:
+    A. "y" is marked n
+
+Always:
+1. For each "u" marked user:
+    A. "u" is marked z"#;
+        assert_eq!(order(policy), vec!["stored <m>", "a"]);
+
+        let (_, parsed) = parse(policy).unwrap();
+        let warnings = Dependencies::new(&parsed.definitions).warnings();
+        assert_eq!(warnings.len(), 1);
+        let (file, warning) = &warnings[0];
+        assert_eq!(*file, None);
+        assert_eq!(warning.location.line, 3);
+        assert_eq!(warning.message, "\"stored <m>\" is used before its definition at 5:5");
+    }
+}
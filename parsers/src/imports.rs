@@ -0,0 +1,34 @@
+use nom::{
+    bytes::complete::tag,
+    character::complete::space1,
+    combinator::cut,
+    error::context,
+    multi::many0,
+    sequence::{delimited, preceded, tuple},
+};
+
+use crate::{Import, Res, Span, common::*};
+
+// Using definitions from "common/storage.txt"
+fn import<'a>(s: Span<'a>) -> Res<Span<'a>, Import<'a>> {
+    let mut combinator = context(
+        "import",
+        preceded(
+            tuple((multispace_comment0, tag("Using definitions from"), space1)),
+            cut(context("import path", delimited(tag("\""), quoted_name, tag("\""))))
+        )
+    );
+    let (remainder, path) = combinator(s)?;
+    Ok((
+        remainder,
+        Import {
+            path: path.fragment(),
+            location: path.into()
+        }
+    ))
+}
+
+// Imports come first in a file, before its definitions
+pub fn parse_imports<'a>(s: Span<'a>) -> Res<Span<'a>, Vec<Import<'a>>> {
+    many0(import)(s)
+}
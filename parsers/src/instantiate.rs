@@ -0,0 +1,325 @@
+use nom::combinator::all_consuming;
+
+use crate::{
+    ASTNode, Clause, ClauseIntro, Definition, Marker, MarkerExpr, Relation, Span, TwoNodeObligation, Variable, VariableIntro,
+    common::identifier, DEFAULT_CONFIG,
+};
+
+// A definition whose name has parameters, like "stored <m>", is a template for many definitions.
+// Using it as "stored sensitive" instantiates it with <m> replaced by sensitive throughout.
+// Each parameter stands in for a marker, e.g. `marked <m>`, or for a variable, e.g. `goes to "<v>"`.
+
+enum Segment<'a> {
+    Literal(&'a str),
+    Parameter(&'a str),
+}
+
+// Whether `name` could be written as a marker
+pub fn is_identifier(name: &str) -> bool {
+    !name.starts_with(char::is_whitespace)
+        && all_consuming(identifier)(Span::new_extra(name, &DEFAULT_CONFIG)).is_ok()
+}
+
+// Split a definition name into literal text and parameters, e.g. "stored <m>" into "stored " and "<m>"
+fn segments(name: &str) -> Vec<Segment<'_>> {
+    let mut segments = vec![];
+    let mut rest = name;
+    let mut search_from = 0;
+    while let Some(open) = rest[search_from..].find('<').map(|idx| idx + search_from) {
+        let parameter = rest[open..].find('>').map(|close| &rest[open..=open + close]);
+        match parameter {
+            Some(parameter) if is_identifier(&parameter[1..parameter.len() - 1]) => {
+                if open > 0 {
+                    segments.push(Segment::Literal(&rest[..open]));
+                }
+                segments.push(Segment::Parameter(parameter));
+                rest = &rest[open + parameter.len()..];
+                search_from = 0;
+            },
+            // a "<" that doesn't start a parameter is just part of the name
+            _ => search_from = open + 1,
+        }
+    }
+    if !rest.is_empty() {
+        segments.push(Segment::Literal(rest));
+    }
+    segments
+}
+
+// Match a use against a template's name, filling in each parameter's argument.
+// A parameter that appears twice must have the same argument both times.
+fn match_segments<'a>(segments: &[Segment<'a>], name: &'a str, arguments: &mut Vec<(&'a str, &'a str)>) -> bool {
+    match segments.split_first() {
+        None => name.is_empty(),
+        Some((Segment::Literal(literal), rest)) => {
+            name.strip_prefix(literal).is_some_and(|name| match_segments(rest, name, arguments))
+        },
+        Some((Segment::Parameter(parameter), rest)) => {
+            // shortest argument first, so "<a> to <b>" splits "x to y to z" at the first "to"
+            let ends = name.char_indices().map(|(idx, _)| idx).skip(1).chain([name.len()]);
+            for end in ends.filter(|&end| end > 0) {
+                let argument = &name[..end];
+                // like variable names, arguments have no surrounding whitespace
+                if argument.trim() != argument {
+                    continue;
+                }
+                let earlier = arguments.iter().find(|(earlier, _)| earlier == parameter).map(|(_, argument)| *argument);
+                if earlier.is_some_and(|earlier| earlier != argument) {
+                    continue;
+                }
+                let len = arguments.len();
+                if earlier.is_none() {
+                    arguments.push((parameter, argument));
+                }
+                if match_segments(rest, &name[end..], arguments) {
+                    return true;
+                }
+                arguments.truncate(len);
+            }
+            false
+        },
+    }
+}
+
+// A definition template with its parameters replaced by a use's arguments
+#[derive(Debug, PartialEq, Eq)]
+pub struct Instance<'a> {
+    pub declaration: VariableIntro<'a>,
+    pub filter: ASTNode<'a>,
+    // arguments that replaced a marker, so they must be marker names
+    pub markers: Vec<&'a str>,
+    // arguments that replaced a variable, so they must be bound where the definition is used
+    pub variables: Vec<&'a str>,
+}
+
+struct Substitution<'a> {
+    arguments: Vec<(&'a str, &'a str)>,
+    markers: Vec<&'a str>,
+    variables: Vec<&'a str>,
+}
+
+impl<'a> Substitution<'a> {
+    fn argument(&self, name: &'a str) -> Option<&'a str> {
+        self.arguments.iter().find(|(parameter, _)| *parameter == name).map(|(_, argument)| *argument)
+    }
+
+    // replaced names keep the location of the parameter in the template
+    fn variable(&mut self, variable: &Variable<'a>) -> Variable<'a> {
+        match self.argument(variable.name) {
+            Some(argument) => {
+                self.variables.push(argument);
+                Variable { name: argument, location: variable.location }
+            },
+            None => *variable,
+        }
+    }
+
+    fn marker(&mut self, marker: &Marker<'a>) -> Marker<'a> {
+        match self.argument(marker.name) {
+            Some(argument) => {
+                self.markers.push(argument);
+                Marker { name: argument, location: marker.location }
+            },
+            None => *marker,
+        }
+    }
+
+    fn marker_expr(&mut self, expr: &MarkerExpr<'a>) -> MarkerExpr<'a> {
+        match expr {
+            MarkerExpr::Marker(marker) => MarkerExpr::Marker(self.marker(marker)),
+            MarkerExpr::And(markers) => MarkerExpr::And(markers.iter().map(|marker| self.marker(marker)).collect()),
+            MarkerExpr::Or(markers) => MarkerExpr::Or(markers.iter().map(|marker| self.marker(marker)).collect()),
+        }
+    }
+
+    fn intro(&mut self, intro: &VariableIntro<'a>) -> VariableIntro<'a> {
+        match intro {
+            VariableIntro::Roots => VariableIntro::Roots,
+            VariableIntro::Variable(var) => VariableIntro::Variable(self.variable(var)),
+            VariableIntro::VariableMarked((var, marker)) => {
+                VariableIntro::VariableMarked((self.variable(var), self.marker_expr(marker)))
+            },
+            VariableIntro::VariableOfTypeMarked((var, marker)) => {
+                VariableIntro::VariableOfTypeMarked((self.variable(var), self.marker_expr(marker)))
+            },
+            VariableIntro::VariableSourceof((var, source_of)) => {
+                VariableIntro::VariableSourceof((self.variable(var), self.variable(source_of)))
+            },
+        }
+    }
+
+    fn relation(&mut self, relation: &Relation<'a>) -> Relation<'a> {
+        match relation {
+            Relation::Influences((src, dest, edges)) => {
+                Relation::Influences((self.variable(src), self.variable(dest), *edges))
+            },
+            Relation::FlowsTo((src, dest, edges)) => Relation::FlowsTo((self.variable(src), self.variable(dest), *edges)),
+            Relation::NoFlowsTo((src, dest, edges)) => {
+                Relation::NoFlowsTo((self.variable(src), self.variable(dest), *edges))
+            },
+            Relation::ControlFlow((src, dest)) => Relation::ControlFlow((self.variable(src), self.variable(dest))),
+            Relation::NoControlFlow((src, dest)) => Relation::NoControlFlow((self.variable(src), self.variable(dest))),
+            Relation::AssociatedCallSite((src, dest)) => {
+                Relation::AssociatedCallSite((self.variable(src), self.variable(dest)))
+            },
+            Relation::HappensBefore((src, dest)) => Relation::HappensBefore((self.variable(src), self.variable(dest))),
+            Relation::HappensAfter((src, dest)) => Relation::HappensAfter((self.variable(src), self.variable(dest))),
+            Relation::IsMarked((var, marker)) => Relation::IsMarked((self.variable(var), self.marker(marker))),
+            Relation::IsNotMarked((var, marker)) => Relation::IsNotMarked((self.variable(var), self.marker(marker))),
+            Relation::OnlyVia(intros) => {
+                let (src, dest, checkpoint) = &**intros;
+                Relation::OnlyVia(Box::new((self.intro(src), self.intro(dest), self.intro(checkpoint))))
+            },
+        }
+    }
+
+    fn filtered_intro(
+        &mut self,
+        intro: &VariableIntro<'a>,
+        filter: &Option<ASTNode<'a>>,
+    ) -> (VariableIntro<'a>, Option<ASTNode<'a>>) {
+        (self.intro(intro), filter.as_ref().map(|filter| self.node(filter)))
+    }
+
+    fn obligation(&mut self, obligation: &TwoNodeObligation<'a>) -> Box<TwoNodeObligation<'a>> {
+        Box::new(TwoNodeObligation { src: self.node(&obligation.src), dest: self.node(&obligation.dest) })
+    }
+
+    fn node(&mut self, node: &ASTNode<'a>) -> ASTNode<'a> {
+        match node {
+            ASTNode::Relation(relation) => ASTNode::Relation(self.relation(relation)),
+            ASTNode::And(obligation) => ASTNode::And(self.obligation(obligation)),
+            ASTNode::Or(obligation) => ASTNode::Or(self.obligation(obligation)),
+            ASTNode::Conditional(obligation) => ASTNode::Conditional(self.obligation(obligation)),
+            ASTNode::Clause(clause) => {
+                let intro = match &clause.intro {
+                    ClauseIntro::ForEach((intro, filter)) => ClauseIntro::ForEach(self.filtered_intro(intro, filter)),
+                    ClauseIntro::ThereIs((intro, filter)) => ClauseIntro::ThereIs(self.filtered_intro(intro, filter)),
+                    ClauseIntro::ThereIsNo((intro, filter)) => ClauseIntro::ThereIsNo(self.filtered_intro(intro, filter)),
+                    ClauseIntro::Counted((count, intro, filter)) => {
+                        let (intro, filter) = self.filtered_intro(intro, filter);
+                        ClauseIntro::Counted((*count, intro, filter))
+                    },
+                    ClauseIntro::Conditional(condition) => ClauseIntro::Conditional(self.node(condition)),
+                    ClauseIntro::Unless(condition) => ClauseIntro::Unless(self.node(condition)),
+                };
+                ASTNode::Clause(Box::new(Clause {
+                    intro,
+                    body: self.node(&clause.body),
+                    otherwise: clause.otherwise.as_ref().map(|otherwise| self.node(otherwise)),
+                    location: clause.location,
+                }))
+            },
+        }
+    }
+}
+
+impl<'a> Definition<'a> {
+    // The parameters in the definition's name, e.g. ["<m>"] for "stored <m>".
+    // A definition without parameters is an ordinary definition, not a template.
+    pub fn parameters(&self) -> Vec<&'a str> {
+        let mut parameters = vec![];
+        for segment in segments(self.variable.name) {
+            if let Segment::Parameter(parameter) = segment {
+                if !parameters.contains(&parameter) {
+                    parameters.push(parameter);
+                }
+            }
+        }
+        parameters
+    }
+
+    // If `name` uses this template, e.g. "stored sensitive" for "stored <m>",
+    // the template's declaration and filter with the use's arguments in place of the parameters
+    pub fn instantiate(&self, name: &'a str) -> Option<Instance<'a>> {
+        let mut arguments = vec![];
+        if self.parameters().is_empty() || !match_segments(&segments(self.variable.name), name, &mut arguments) {
+            return None;
+        }
+        let mut substitution = Substitution { arguments, markers: vec![], variables: vec![] };
+        let declaration = substitution.intro(&self.declaration);
+        let filter = substitution.node(&self.filter);
+        Some(Instance { declaration, filter, markers: substitution.markers, variables: substitution.variables })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+
+    // The arguments `name` fills the template's parameters with, if it uses the template
+    fn arguments<'a>(template: &'a str, name: &'a str) -> Option<Vec<(&'a str, &'a str)>> {
+        let mut arguments = vec![];
+        match_segments(&segments(template), name, &mut arguments).then_some(arguments)
+    }
+
+    fn parameters(template: &str) -> Vec<&str> {
+        segments(template)
+            .into_iter()
+            .filter_map(|segment| match segment {
+                Segment::Parameter(parameter) => Some(parameter),
+                Segment::Literal(_) => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    pub fn test_multiple_parameters() {
+        assert_eq!(parameters("<d> checked by <c>"), vec!["<d>", "<c>"]);
+        assert_eq!(
+            arguments("<d> checked by <c>", "deleter checked by auth"),
+            Some(vec![("<d>", "deleter"), ("<c>", "auth")])
+        );
+        assert_eq!(arguments("<d> checked by <c>", "deleter checked"), None);
+        assert_eq!(arguments("stored <m>", "stored "), None);
+    }
+
+    #[test]
+    pub fn test_repeated_parameter() {
+        assert_eq!(arguments("<a> to <a>", "x to x"), Some(vec![("<a>", "x")]));
+        assert_eq!(arguments("<a> to <a>", "x to y"), None);
+        // the first occurrence has to backtrack past "x to" to agree with the second
+        assert_eq!(arguments("<a> to <a>", "x to y to x to y"), Some(vec![("<a>", "x to y")]));
+    }
+
+    #[test]
+    pub fn test_stray_angle_bracket() {
+        assert_eq!(parameters("a < b <m>"), vec!["<m>"]);
+        assert_eq!(arguments("a < b <m>", "a < b sensitive"), Some(vec![("<m>", "sensitive")]));
+        assert!(parameters("a <b").is_empty());
+        assert!(parameters("a < b > c").is_empty());
+        assert_eq!(parameters("<<m>>"), vec!["<m>"]);
+        assert_eq!(arguments("<<m>>", "<x>"), Some(vec![("<m>", "x")]));
+    }
+
+    #[test]
+    pub fn test_overlapping_literals() {
+        // the first parameter takes the shortest argument that lets the rest match
+        assert_eq!(arguments("<a> to <b>", "x to y to z"), Some(vec![("<a>", "x"), ("<b>", "y to z")]));
+        assert_eq!(arguments("<a> to <b> to z", "x to y to z"), Some(vec![("<a>", "x"), ("<b>", "y")]));
+        assert_eq!(arguments("<a>to<b>", "totoo"), Some(vec![("<a>", "to"), ("<b>", "o")]));
+        // arguments can't start or end with whitespace
+        assert_eq!(arguments("<a> to <b>", "x  to y"), None);
+    }
+
+    #[test]
+    pub fn test_instantiate() {
+        let policy = "Definitions:\n1. \"stored <m>\" is each \"x\" marked <m> where:\n    A. There is a \"store\" marked store where:\n        a. \"x\" goes to \"store\"\n2. \"store\" is each \"s\" marked store where:\n    A. \"s\" is marked sink\n\nAlways:\n1. For each \"stored sensitive\":\n    A. \"stored sensitive\" is marked sensitive";
+        let (_, policy) = parse(policy).unwrap();
+        let template = &policy.definitions[0];
+        assert_eq!(template.parameters(), vec!["<m>"]);
+
+        let instance = template.instantiate("stored sensitive").unwrap();
+        assert_eq!(instance.markers, vec!["sensitive"]);
+        assert!(instance.variables.is_empty());
+        let VariableIntro::VariableMarked((var, MarkerExpr::Marker(marker))) = instance.declaration else {
+            panic!("expected a marked declaration, got {:?}", instance.declaration);
+        };
+        assert_eq!((var.name, marker.name), ("x", "sensitive"));
+
+        assert_eq!(template.instantiate("stored"), None);
+        // a definition without parameters isn't a template
+        assert_eq!(policy.definitions[1].instantiate("store"), None);
+    }
+}
@@ -1,35 +1,272 @@
+use std::{borrow::Cow, fmt::Display, str::FromStr};
+
 use definitions::parse_definitions;
-use nom::{IResult, error::{VerboseError, context}, combinator::{all_consuming, opt}, sequence::tuple};
-use policy_body::parse_policy_body;
+use common::multispace_comment0;
+use nom::{IResult, error::{VerboseError, context}, combinator::{all_consuming, opt}, sequence::{terminated, tuple}};
+use nom_locate::LocatedSpan;
+use imports::parse_imports;
+use policy_body::parse_policy_bodies;
 
 pub type Res<T, U> = IResult<T, U, VerboseError<T>>;
 
+// Input to the parsers; tracks the line and column of each fragment
+// and carries the configuration along to every parser
+pub type Span<'a> = LocatedSpan<&'a str, &'a ParseConfig>;
+
+// Parser configuration
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum BulletKind {
+    Numeric,
+    UpperAlpha,
+    LowerAlpha,
+    LowerRoman,
+    UpperRoman,
+}
+
+impl BulletKind {
+    // The position of a bullet label in its list, starting at 1, e.g. "c" is 3 and "iv" is 4.
+    // Letters past "z" continue with "aa", "ab", and so on.
+    pub fn ordinal(&self, label: &str) -> Option<usize> {
+        match self {
+            BulletKind::Numeric => label.parse().ok().filter(|&n| n > 0),
+            BulletKind::UpperAlpha | BulletKind::LowerAlpha => label
+                .chars()
+                .try_fold(0usize, |acc, c| acc.checked_mul(26)?.checked_add(c.to_ascii_lowercase() as usize - 'a' as usize + 1)),
+            BulletKind::LowerRoman | BulletKind::UpperRoman => roman_numeral(label),
+        }
+    }
+}
+
+const ROMAN_NUMERALS: [(usize, &str); 13] = [
+    (1000, "m"), (900, "cm"), (500, "d"), (400, "cd"), (100, "c"), (90, "xc"),
+    (50, "l"), (40, "xl"), (10, "x"), (9, "ix"), (5, "v"), (4, "iv"), (1, "i"),
+];
+
+fn to_roman_numeral(mut value: usize) -> String {
+    let mut numeral = String::new();
+    for (numeral_value, digits) in ROMAN_NUMERALS {
+        while value >= numeral_value {
+            numeral.push_str(digits);
+            value -= numeral_value;
+        }
+    }
+    numeral
+}
+
+// Only accepts numerals in their standard form, so "iiii" and "ixi" are not numerals
+fn roman_numeral(label: &str) -> Option<usize> {
+    let label = label.to_ascii_lowercase();
+    let mut rest = label.as_str();
+    let mut value = 0;
+    for (numeral_value, digits) in ROMAN_NUMERALS {
+        while let Some(stripped) = rest.strip_prefix(digits) {
+            rest = stripped;
+            value += numeral_value;
+        }
+    }
+    (rest.is_empty() && value > 0 && to_roman_numeral(value) == label).then_some(value)
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum BulletDelimiter {
+    // 1.
+    Period,
+    // 1)
+    Paren,
+    // (1)
+    Parens,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct BulletStyle {
+    pub kind: BulletKind,
+    pub delimiter: BulletDelimiter,
+}
+
+// Styles are written the way the first bullet of the level would be, e.g. "A." or "(i)"
+impl FromStr for BulletStyle {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (label, delimiter) = if let Some(label) = s.strip_prefix('(').and_then(|s| s.strip_suffix(')')) {
+            (label, BulletDelimiter::Parens)
+        } else if let Some(label) = s.strip_suffix(')') {
+            (label, BulletDelimiter::Paren)
+        } else if let Some(label) = s.strip_suffix('.') {
+            (label, BulletDelimiter::Period)
+        } else {
+            return Err(format!("bullet style {s} must end in \".\" or \")\""));
+        };
+        let kind = match label {
+            "1" => BulletKind::Numeric,
+            "A" => BulletKind::UpperAlpha,
+            "a" => BulletKind::LowerAlpha,
+            "i" => BulletKind::LowerRoman,
+            "I" => BulletKind::UpperRoman,
+            _ => return Err(format!("bullet style {s} must be numbered with 1, A, a, i or I")),
+        };
+        Ok(BulletStyle { kind, delimiter })
+    }
+}
+
+impl Display for BulletStyle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let label = match self.kind {
+            BulletKind::Numeric => "1",
+            BulletKind::UpperAlpha => "A",
+            BulletKind::LowerAlpha => "a",
+            BulletKind::LowerRoman => "i",
+            BulletKind::UpperRoman => "I",
+        };
+        match self.delimiter {
+            BulletDelimiter::Period => write!(f, "{label}."),
+            BulletDelimiter::Paren => write!(f, "{label})"),
+            BulletDelimiter::Parens => write!(f, "({label})"),
+        }
+    }
+}
+
+const fn style(kind: BulletKind, delimiter: BulletDelimiter) -> BulletStyle {
+    BulletStyle { kind, delimiter }
+}
+
+// 1. / A. / a. / i) / A) / 1) / (a) / (A) / (1)
+// No two of these can be mistaken for each other, so a bullet always tells the parser which level it is on.
+pub const DEFAULT_BULLET_STYLES: [BulletStyle; 9] = [
+    style(BulletKind::Numeric, BulletDelimiter::Period),
+    style(BulletKind::UpperAlpha, BulletDelimiter::Period),
+    style(BulletKind::LowerAlpha, BulletDelimiter::Period),
+    style(BulletKind::LowerRoman, BulletDelimiter::Paren),
+    style(BulletKind::UpperAlpha, BulletDelimiter::Paren),
+    style(BulletKind::Numeric, BulletDelimiter::Paren),
+    style(BulletKind::LowerAlpha, BulletDelimiter::Parens),
+    style(BulletKind::UpperAlpha, BulletDelimiter::Parens),
+    style(BulletKind::Numeric, BulletDelimiter::Parens),
+];
+
+// Built with ParseConfig::new, which checks that the parser can use it
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ParseConfig {
+    // bullet style of each nesting level, starting with the top level.
+    // Levels nested deeper than this list start over from the beginning,
+    // so list enough styles that a deep bullet can't be mistaken for a shallow one.
+    bullet_styles: Cow<'static, [BulletStyle]>,
+    // columns between tab stops, for measuring indentation that uses tabs
+    tab_width: usize,
+}
+
+impl ParseConfig {
+    pub fn new(bullet_styles: Vec<BulletStyle>, tab_width: usize) -> Result<Self, String> {
+        if bullet_styles.is_empty() {
+            return Err("there must be at least one bullet style, for the top level".to_string());
+        }
+        if tab_width == 0 {
+            return Err("tab width must be at least 1".to_string());
+        }
+        Ok(ParseConfig { bullet_styles: Cow::Owned(bullet_styles), tab_width })
+    }
+
+    // levels start at 1
+    pub fn bullet_style(&self, level: usize) -> BulletStyle {
+        self.bullet_styles[(level - 1) % self.bullet_styles.len()]
+    }
+
+    pub fn bullet_styles(&self) -> &[BulletStyle] {
+        &self.bullet_styles
+    }
+
+    pub fn tab_width(&self) -> usize {
+        self.tab_width
+    }
+}
+
+pub static DEFAULT_CONFIG: ParseConfig = ParseConfig {
+    bullet_styles: Cow::Borrowed(&DEFAULT_BULLET_STYLES),
+    tab_width: 4,
+};
+
+impl Default for ParseConfig {
+    fn default() -> Self {
+        DEFAULT_CONFIG.clone()
+    }
+}
+
+// Where in the policy file an AST node starts
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct Location {
+    pub line: u32,
+    pub column: usize,
+}
+
+impl<'a> From<Span<'a>> for Location {
+    fn from(span: Span<'a>) -> Self {
+        Location {
+            line: span.location_line(),
+            column: span.get_utf8_column()
+        }
+    }
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
 // Top-level policy / definition data
 #[derive(Debug, PartialEq, Eq)]
 pub struct Policy<'a> {
-    definitions: Vec<Definition<'a>>,
-    body: PolicyBody<'a>,
+    pub imports: Vec<Import<'a>>,
+    pub definitions: Vec<Definition<'a>>,
+    // every body shares the definitions
+    pub bodies: Vec<PolicyBody<'a>>,
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum PolicyScope<'a> {
     Always,
     Sometimes,
-    InCtrler(&'a str)
+    InCtrler(ControllerSelector<'a>)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ControllerSelector<'a> {
+    // In gdpr_deletes, account_purge:
+    Named(Vec<&'a str>),
+    // In controllers matching "*_deletes":
+    // * matches any run of characters and ? matches any one character
+    Matching(&'a str),
+    // In all controllers except gdpr_deletes, account_purge:
+    Except(Vec<&'a str>),
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct PolicyBody<'a> {
-    scope: PolicyScope<'a>,
-    body: ASTNode<'a>,
+    // quoted like a variable, e.g. Policy "community deletion":
+    // None when the file holds a single unnamed policy
+    pub name: Option<Variable<'a>>,
+    pub scope: PolicyScope<'a>,
+    pub body: ASTNode<'a>,
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct Definition<'a> {
     // quantifier is always "all" bc definitions are over *each* var that satisifes condition
-    variable: Variable<'a>,
-    declaration: VariableIntro<'a>,
-    filter: ASTNode<'a>
+    // A name with parameters, like "stored <m>", makes the definition a template; see instantiate.rs
+    // The variables bound inside the definition aren't visible outside of it; see resolve.rs
+    pub variable: Variable<'a>,
+    pub declaration: VariableIntro<'a>,
+    pub filter: ASTNode<'a>,
+    // the file this definition was imported from, or None if it's written in the policy file itself
+    pub imported_from: Option<&'a str>,
+}
+
+// Using definitions from "common/storage.txt"
+// The path is relative to the file that contains the import.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Import<'a> {
+    pub path: &'a str,
+    pub location: Location,
 }
 
 // AST data
@@ -37,26 +274,146 @@ pub struct Definition<'a> {
 pub enum VariableIntro<'a> {
     Roots,
     Variable(Variable<'a>),
-    VariableMarked((Variable<'a>, Marker<'a>)),
-    VariableOfTypeMarked((Variable<'a>, Marker<'a>)),
+    VariableMarked((Variable<'a>, MarkerExpr<'a>)),
+    VariableOfTypeMarked((Variable<'a>, MarkerExpr<'a>)),
     VariableSourceof((Variable<'a>, Variable<'a>))
 }
 
+// Which edges a flow relation follows, e.g. "goes to (via control)".
+// `goes to` follows data edges unless it says otherwise, and `influences` follows both.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum EdgeSelection {
+    // (data only)
+    Data,
+    // (via control)
+    Control,
+    // (data or control)
+    DataAndControl,
+}
+
 #[derive(Debug, PartialEq, Eq, Hash)]
 pub enum Relation<'a> {
-    Influences((Variable<'a>, Variable<'a>)),
-    FlowsTo((Variable<'a>, Variable<'a>)),
-    NoFlowsTo((Variable<'a>, Variable<'a>)),
+    Influences((Variable<'a>, Variable<'a>, EdgeSelection)),
+    FlowsTo((Variable<'a>, Variable<'a>, EdgeSelection)),
+    NoFlowsTo((Variable<'a>, Variable<'a>, EdgeSelection)),
     ControlFlow((Variable<'a>, Variable<'a>)),
     NoControlFlow((Variable<'a>, Variable<'a>)),
     AssociatedCallSite((Variable<'a>, Variable<'a>)),
+    // every path from the controller's roots to the second variable passes through the first
+    HappensBefore((Variable<'a>, Variable<'a>)),
+    // the same, with the variables the other way around
+    HappensAfter((Variable<'a>, Variable<'a>)),
     IsMarked((Variable<'a>, Marker<'a>)),
     IsNotMarked((Variable<'a>, Marker<'a>)),
-    OnlyVia((VariableIntro<'a>, VariableIntro<'a>, VariableIntro<'a>))
+    // boxed, since its intros are much larger than the other relations
+    OnlyVia(Box<(VariableIntro<'a>, VariableIntro<'a>, VariableIntro<'a>)>)
+}
+
+impl<'a> VariableIntro<'a> {
+    // Roots are introduced by a keyword, so they have no location of their own
+    pub fn location(&self) -> Option<Location> {
+        match self {
+            VariableIntro::Roots => None,
+            VariableIntro::Variable(var)
+            | VariableIntro::VariableMarked((var, _))
+            | VariableIntro::VariableOfTypeMarked((var, _))
+            | VariableIntro::VariableSourceof((var, _)) => Some(var.location),
+        }
+    }
+
+    // The variable being introduced, and the one it's the source of, if any
+    pub fn variables(&self) -> Vec<Variable<'a>> {
+        match self {
+            VariableIntro::VariableSourceof((var, source_of)) => vec![*var, *source_of],
+            _ => self.variable().into_iter().collect(),
+        }
+    }
+
+    // The variable being introduced, if there is one
+    pub fn variable(&self) -> Option<Variable<'a>> {
+        match self {
+            VariableIntro::Roots => None,
+            VariableIntro::Variable(var)
+            | VariableIntro::VariableMarked((var, _))
+            | VariableIntro::VariableOfTypeMarked((var, _))
+            | VariableIntro::VariableSourceof((var, _)) => Some(*var),
+        }
+    }
+}
+
+impl<'a> Relation<'a> {
+    pub fn variables(&self) -> Vec<Variable<'a>> {
+        match self {
+            Relation::Influences((src, dest, _))
+            | Relation::FlowsTo((src, dest, _))
+            | Relation::NoFlowsTo((src, dest, _)) => vec![*src, *dest],
+            Relation::ControlFlow((src, dest))
+            | Relation::NoControlFlow((src, dest))
+            | Relation::AssociatedCallSite((src, dest))
+            | Relation::HappensBefore((src, dest))
+            | Relation::HappensAfter((src, dest)) => vec![*src, *dest],
+            Relation::IsMarked((var, _)) | Relation::IsNotMarked((var, _)) => vec![*var],
+            Relation::OnlyVia(intros) => {
+                let (src, dest, checkpoint) = &**intros;
+                [src, dest, checkpoint].iter().flat_map(|intro| intro.variables()).collect()
+            },
+        }
+    }
+
+    // A relation starts where its first variable does
+    pub fn location(&self) -> Location {
+        match self {
+            Relation::Influences((var, _, _))
+            | Relation::FlowsTo((var, _, _))
+            | Relation::NoFlowsTo((var, _, _)) => var.location,
+            Relation::ControlFlow((var, _))
+            | Relation::NoControlFlow((var, _))
+            | Relation::AssociatedCallSite((var, _))
+            | Relation::HappensBefore((var, _))
+            | Relation::HappensAfter((var, _)) => var.location,
+            Relation::IsMarked((var, _)) | Relation::IsNotMarked((var, _)) => var.location,
+            Relation::OnlyVia(intros) => {
+                let (src, dest, _) = &**intros;
+                // "goes to a" always takes a variable, so this only fails if src and dest are both roots
+                src.location()
+                    .or(dest.location())
+                    .expect("only via relation must have a located variable")
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct Variable<'a> {
+    pub name: &'a str,
+    pub location: Location,
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct Marker<'a> {
+    pub name: &'a str,
+    pub location: Location,
 }
 
-pub type Variable<'a> = &'a str;
-pub type Marker<'a> = &'a str;
+// The markers in an intro, e.g. `marked hash or apikey_response`.
+// Like clauses, one expression can't mix `and` with `or`.
+#[derive(Debug, PartialEq, Eq, Hash)]
+pub enum MarkerExpr<'a> {
+    Marker(Marker<'a>),
+    // nodes with every one of the markers
+    And(Vec<Marker<'a>>),
+    // nodes with at least one of the markers
+    Or(Vec<Marker<'a>>),
+}
+
+impl<'a> MarkerExpr<'a> {
+    pub fn markers(&self) -> &[Marker<'a>] {
+        match self {
+            MarkerExpr::Marker(marker) => std::slice::from_ref(marker),
+            MarkerExpr::And(markers) | MarkerExpr::Or(markers) => markers,
+        }
+    }
+}
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum Operator {
@@ -76,21 +433,42 @@ impl From<&str> for Operator {
 
 #[derive(Debug, PartialEq, Eq, Hash)]
 pub struct TwoNodeObligation<'a> {
-    src: ASTNode<'a>,
-    dest: ASTNode<'a>,
+    pub src: ASTNode<'a>,
+    pub dest: ASTNode<'a>,
 }
 
 #[derive(Debug, PartialEq, Eq, Hash)]
 pub enum ClauseIntro<'a> {
-    ForEach(VariableIntro<'a>),
-    ThereIs(VariableIntro<'a>),
-    Conditional(Relation<'a>)
+    // the optional filter restricts which nodes are quantified over, e.g. "For each "a" that goes to "b":"
+    ForEach((VariableIntro<'a>, Option<ASTNode<'a>>)),
+    ThereIs((VariableIntro<'a>, Option<ASTNode<'a>>)),
+    ThereIsNo((VariableIntro<'a>, Option<ASTNode<'a>>)),
+    // There is exactly one "check" marked check where:
+    Counted((Count, VariableIntro<'a>, Option<ASTNode<'a>>)),
+    // If "a" goes to "b" and "b" is not marked internal then:
+    // the condition is relations joined by `and` or `or`, and can quantify, e.g. If there is a "c" where "c" goes to "b" then:
+    // variables a condition introduces with `there is a` are bound in the body, unless they're under an `or`
+    Conditional(ASTNode<'a>),
+    // Unless "a" is marked safe:
+    Unless(ASTNode<'a>),
+}
+
+// How many nodes a counting clause requires to satisfy its body
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum Count {
+    AtLeast(usize),
+    AtMost(usize),
+    Exactly(usize),
 }
 
 #[derive(Debug, PartialEq, Eq, Hash)]
 pub struct Clause<'a> {
-    intro: ClauseIntro<'a>,
-    body: ASTNode<'a>
+    pub intro: ClauseIntro<'a>,
+    pub body: ASTNode<'a>,
+    // the Otherwise: block of an If or Unless clause, which must hold when the body doesn't apply
+    pub otherwise: Option<ASTNode<'a>>,
+    // location of the clause's bullet
+    pub location: Location,
 }
 
 #[derive(Debug, PartialEq, Eq, Hash)]
@@ -102,22 +480,185 @@ pub enum ASTNode<'a> {
     Clause(Box<Clause<'a>>)
 }
 
-pub fn parse<'a>(s: &'a str) -> Res<&str, Policy<'a>> {
+impl<'a> ASTNode<'a> {
+    // The parts of a condition joined by `and`; a condition joined by `or` is a single part
+    pub fn conjuncts(&self) -> Vec<&ASTNode<'a>> {
+        match self {
+            ASTNode::And(obligation) => {
+                let mut conjuncts = obligation.src.conjuncts();
+                conjuncts.extend(obligation.dest.conjuncts());
+                conjuncts
+            },
+            _ => vec![self],
+        }
+    }
+
+    // Every variable the node binds or refers to, in order, with repeats
+    pub fn variables(&self) -> Vec<Variable<'a>> {
+        match self {
+            ASTNode::Relation(relation) => relation.variables(),
+            ASTNode::And(obligation) | ASTNode::Or(obligation) | ASTNode::Conditional(obligation) => {
+                let mut variables = obligation.src.variables();
+                variables.extend(obligation.dest.variables());
+                variables
+            },
+            ASTNode::Clause(clause) => {
+                let mut variables = match &clause.intro {
+                    ClauseIntro::ForEach((intro, filter))
+                    | ClauseIntro::ThereIs((intro, filter))
+                    | ClauseIntro::ThereIsNo((intro, filter))
+                    | ClauseIntro::Counted((_, intro, filter)) => {
+                        let mut variables = intro.variables();
+                        variables.extend(filter.iter().flat_map(|filter| filter.variables()));
+                        variables
+                    },
+                    ClauseIntro::Conditional(condition) | ClauseIntro::Unless(condition) => condition.variables(),
+                };
+                variables.extend(clause.body.variables());
+                variables.extend(clause.otherwise.iter().flat_map(|otherwise| otherwise.variables()));
+                variables
+            },
+        }
+    }
+}
+
+pub fn parse(s: &str) -> Res<Span<'_>, Policy<'_>> {
+    parse_with_config(s, &DEFAULT_CONFIG)
+}
+
+pub fn parse_with_config<'a>(s: &'a str, config: &'a ParseConfig) -> Res<Span<'a>, Policy<'a>> {
     let mut combinator = context(
         "parse policy", 
         all_consuming(
-            tuple((opt(parse_definitions), parse_policy_body))
+            // trailing whitespace and comments are fine; anything else is not
+            terminated(tuple((parse_imports, opt(parse_definitions), parse_policy_bodies)), multispace_comment0)
         )
     );
 
-    let (remainder, (option_defs, body)) = combinator(s)?;
-    Ok((remainder, Policy {definitions: option_defs.unwrap_or_default(), body}))
+    let (remainder, (imports, option_defs, bodies)) = combinator(Span::new_extra(s, config))?;
+    Ok((remainder, Policy {imports, definitions: option_defs.unwrap_or_default(), bodies}))
+}
+
+// A file of shared definitions, which policies import; it has no policy body of its own.
+pub fn parse_definitions_file_with_config<'a>(s: &'a str, config: &'a ParseConfig) -> Res<Span<'a>, (Vec<Import<'a>>, Vec<Definition<'a>>)> {
+    context(
+        "parse definitions file",
+        all_consuming(terminated(tuple((parse_imports, parse_definitions)), multispace_comment0))
+    )(Span::new_extra(s, config))
 }
 
 pub mod common;
 pub mod clause;
 pub mod definitions;
+pub mod dependencies;
+pub mod imports;
+pub mod instantiate;
+pub mod lint;
+pub mod loader;
 pub mod policy_body;
 pub mod relations;
+pub mod report;
+pub mod resolve;
 pub mod scope;
-pub mod variable_intro;
\ No newline at end of file
+pub mod variable_intro;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_roman_numeral() {
+        assert_eq!(roman_numeral("i"), Some(1));
+        assert_eq!(roman_numeral("iv"), Some(4));
+        assert_eq!(roman_numeral("ix"), Some(9));
+        assert_eq!(roman_numeral("xliv"), Some(44));
+        assert_eq!(roman_numeral("MCMXCIV"), Some(1994));
+
+        // only the standard form of each numeral
+        assert_eq!(roman_numeral("iiii"), None);
+        assert_eq!(roman_numeral("ixi"), None);
+        assert_eq!(roman_numeral("vv"), None);
+        assert_eq!(roman_numeral("il"), None);
+        assert_eq!(roman_numeral(""), None);
+    }
+
+    #[test]
+    pub fn test_ordinal() {
+        assert_eq!(BulletKind::Numeric.ordinal("1"), Some(1));
+        assert_eq!(BulletKind::Numeric.ordinal("12"), Some(12));
+        assert_eq!(BulletKind::Numeric.ordinal("0"), None);
+
+        assert_eq!(BulletKind::LowerAlpha.ordinal("a"), Some(1));
+        assert_eq!(BulletKind::LowerAlpha.ordinal("z"), Some(26));
+        assert_eq!(BulletKind::LowerAlpha.ordinal("aa"), Some(27));
+        assert_eq!(BulletKind::UpperAlpha.ordinal("AB"), Some(28));
+
+        assert_eq!(BulletKind::LowerRoman.ordinal("xliv"), Some(44));
+        assert_eq!(BulletKind::UpperRoman.ordinal("IV"), Some(4));
+        assert_eq!(BulletKind::LowerRoman.ordinal("iiii"), None);
+    }
+
+    #[test]
+    pub fn test_bullet_style_round_trip() {
+        for style in DEFAULT_BULLET_STYLES {
+            assert_eq!(style.to_string().parse::<BulletStyle>(), Ok(style));
+        }
+        assert!("1".parse::<BulletStyle>().is_err());
+        assert!("b.".parse::<BulletStyle>().is_err());
+    }
+
+    #[test]
+    pub fn test_location() {
+        let policy = "Definitions:\n1. \"sensitive sink\" is each \"sink\" marked sink where:\n    A. \"sink\" is marked sensitive\n\nAlways:\n1. For each \"données\" marked x:\n    A. \"données\" goes to \"sensitive sink\"";
+        let (_, parsed) = parse(policy).unwrap();
+        let definition = &parsed.definitions[0];
+        assert_eq!(definition.variable.location, Location { line: 2, column: 5 });
+        assert_eq!(definition.declaration.location(), Some(Location { line: 2, column: 30 }));
+
+        let ASTNode::Clause(clause) = &parsed.bodies[0].body else {
+            panic!("expected a clause, got {:?}", parsed.bodies[0].body);
+        };
+        // a clause is located at its bullet, and columns count characters rather than bytes
+        assert_eq!(clause.location, Location { line: 6, column: 1 });
+        let ASTNode::Relation(Relation::FlowsTo((src, dest, _))) = &clause.body else {
+            panic!("expected a relation, got {:?}", clause.body);
+        };
+        assert_eq!((src.location, dest.location), (Location { line: 7, column: 9 }, Location { line: 7, column: 27 }));
+
+        let skipped = "Always:\n1. For each \"a\" marked x:\n    A. \"a\" is marked y\n    and\n    C. \"a\" is marked z";
+        assert_eq!(common::parse_failure(skipped), Some(("bullet out of sequence", Location { line: 5, column: 5 })));
+    }
+
+    #[test]
+    pub fn test_config() {
+        assert!(ParseConfig::new(vec![], 4).is_err());
+        assert!(ParseConfig::new(DEFAULT_BULLET_STYLES.to_vec(), 0).is_err());
+        assert_eq!(ParseConfig::new(DEFAULT_BULLET_STYLES.to_vec(), 4), Ok(DEFAULT_CONFIG.clone()));
+    }
+
+    #[test]
+    pub fn test_deep_nesting() {
+        // the tenth level starts the default styles over
+        let policy = r#"Always:
+1. For each "a" marked x:
+    A. For each "b" marked x:
+        a. For each "c" marked x:
+            i) For each "d" marked x:
+                A) For each "e" marked x:
+                    1) For each "f" marked x:
+                        (a) For each "g" marked x:
+                            (A) For each "h" marked x:
+                                (1) For each "i" marked x:
+                                    1. For each "j" marked x:
+                                        A. "j" is marked y
+                                        and
+                                        B. "a" goes to "j""#;
+        let (_, policy) = parse(policy).unwrap();
+        assert_eq!(policy.bodies[0].body.variables().len(), 13);
+
+        // with two styles, every other level looks the same
+        let config = ParseConfig::new(vec!["1.".parse().unwrap(), "a)".parse().unwrap()], 4).unwrap();
+        let alternating = "Always:\n1. For each \"a\" marked x:\n    a) For each \"b\" marked x:\n        1. For each \"c\" marked x:\n            a) \"c\" is marked y";
+        assert!(parse_with_config(alternating, &config).is_ok());
+        assert!(parse(alternating).is_err());
+    }
+}
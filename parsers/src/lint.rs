@@ -0,0 +1,41 @@
+use crate::Location;
+
+// Something the parser accepts, but that is probably not what the author meant
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Warning {
+    pub location: Location,
+    pub message: String,
+}
+
+// Indentation that mixes tabs and spaces only lines up at one tab width,
+// so the nesting an author sees in their editor may not be the nesting the parser checks.
+pub fn mixed_indentation(source: &str, tab_width: usize) -> Vec<Warning> {
+    source
+        .lines()
+        .enumerate()
+        .filter_map(|(idx, line)| {
+            let indentation: &str = &line[..line.len() - line.trim_start_matches([' ', '\t']).len()];
+            (indentation.contains(' ') && indentation.contains('\t')).then(|| Warning {
+                location: Location { line: idx as u32 + 1, column: 1 },
+                message: format!("indentation mixes tabs and spaces; tabs are read as stops every {tab_width} columns"),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_mixed_indentation() {
+        let source = "Always:\n1. For each \"a\" marked x:\n \tA. \"a\" is marked y\n\tand\n\tB. \"a\" is marked z\n    and\n\t  C. \"a\" is marked w";
+        let warnings = mixed_indentation(source, 4);
+        let locations: Vec<Location> = warnings.iter().map(|warning| warning.location).collect();
+        assert_eq!(locations, vec![Location { line: 3, column: 1 }, Location { line: 7, column: 1 }]);
+        assert_eq!(warnings[0].message, "indentation mixes tabs and spaces; tabs are read as stops every 4 columns");
+
+        // spaces after the bullet aren't indentation
+        assert!(mixed_indentation("Always:\n\t1.\t \"a\" is marked y", 4).is_empty());
+    }
+}
@@ -0,0 +1,270 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    imports::parse_imports, parse_definitions_file_with_config, parse_with_config,
+    report::render_parse_error, Location, ParseConfig, Policy, Span,
+};
+
+// A policy file, or a definitions file that it imports
+#[derive(Debug)]
+pub struct SourceFile {
+    // as written, joined onto the directory of the file that imported it
+    pub path: String,
+    pub source: String,
+}
+
+// The policy file along with every file it imports, directly or indirectly.
+// The parsed policy borrows from the sources, so they're loaded before anything is parsed.
+#[derive(Debug)]
+pub struct Sources<'c> {
+    // each file comes after the files it imports, so the policy file is last
+    pub files: Vec<SourceFile>,
+    config: &'c ParseConfig,
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    // `imported_at` is the file and location of the import, unless it's the policy file itself
+    Read { path: String, error: std::io::Error, imported_at: Option<(String, Location)> },
+    // already rendered, with the file's path
+    Parse(String),
+    // each file in the cycle, starting and ending with the same one
+    Cycle { cycle: Vec<String>, imported_at: (String, Location) },
+    Collision { name: String, first: (String, Location), second: (String, Location) },
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadError::Read { path, error, imported_at: None } => write!(f, "could not read {path}: {error}"),
+            LoadError::Read { path, error, imported_at: Some((file, location)) } => {
+                write!(f, "{file}:{location}: could not read imported file {path}: {error}")
+            },
+            LoadError::Parse(report) => write!(f, "{report}"),
+            LoadError::Cycle { cycle, imported_at: (file, location) } => {
+                write!(f, "{file}:{location}: definitions are imported in a cycle: {}", cycle.join(" -> "))
+            },
+            LoadError::Collision { name, first: (first_file, first_location), second: (second_file, second_location) } => {
+                write!(
+                    f,
+                    "{second_file}:{second_location}: definition \"{name}\" is already defined at {first_file}:{first_location}"
+                )
+            },
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+fn read(path: &str, imported_at: Option<(String, Location)>) -> Result<String, LoadError> {
+    fs::read_to_string(path).map_err(|error| LoadError::Read { path: path.to_string(), error, imported_at })
+}
+
+// Identifies a file no matter which relative path it was imported by
+fn identity(path: &str) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path))
+}
+
+impl<'c> Sources<'c> {
+    pub fn load(policy_file: &str, config: &'c ParseConfig) -> Result<Self, LoadError> {
+        let mut sources = Sources { files: vec![], config };
+        let source = read(policy_file, None)?;
+        let mut loaded = HashSet::new();
+        sources.load_imports(policy_file, source, &mut vec![], &mut loaded)?;
+        Ok(sources)
+    }
+
+    // Depth first, so that each file is added after the files it imports.
+    // `stack` holds the files currently being loaded, to detect cycles.
+    fn load_imports(
+        &mut self,
+        path: &str,
+        source: String,
+        stack: &mut Vec<(PathBuf, String)>,
+        loaded: &mut HashSet<PathBuf>,
+    ) -> Result<(), LoadError> {
+        let imports: Vec<(String, Location)> = {
+            let (_, imports) = parse_imports(Span::new_extra(&source, self.config))
+                .map_err(|e| LoadError::Parse(render_parse_error(&e, &source, path)))?;
+            let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+            imports
+                .iter()
+                .map(|import| (dir.join(import.path).to_string_lossy().into_owned(), import.location))
+                .collect()
+        };
+
+        stack.push((identity(path), path.to_string()));
+        for (import_path, location) in imports {
+            let id = identity(&import_path);
+            if let Some(start) = stack.iter().position(|(on_stack, _)| *on_stack == id) {
+                let mut cycle: Vec<String> = stack[start..].iter().map(|(_, path)| path.clone()).collect();
+                cycle.push(import_path);
+                return Err(LoadError::Cycle { cycle, imported_at: (path.to_string(), location) });
+            }
+            // a file imported by several others only needs to be loaded once
+            if loaded.contains(&id) {
+                continue;
+            }
+            let source = read(&import_path, Some((path.to_string(), location)))?;
+            self.load_imports(&import_path, source, stack, loaded)?;
+        }
+        let (id, _) = stack.pop().expect("pushed above");
+        loaded.insert(id);
+        self.files.push(SourceFile { path: path.to_string(), source });
+        Ok(())
+    }
+
+    // Parse every file, adding the imported definitions to the policy's own.
+    pub fn parse(&self) -> Result<Policy<'_>, LoadError> {
+        let (policy_file, imported) = self.files.split_last().expect("the policy file is always loaded");
+        let mut definitions = vec![];
+        for file in imported {
+            let (_, (_, file_definitions)) = parse_definitions_file_with_config(&file.source, self.config)
+                .map_err(|e| LoadError::Parse(render_parse_error(&e, &file.source, &file.path)))?;
+            definitions.extend(file_definitions.into_iter().map(|definition| crate::Definition {
+                imported_from: Some(file.path.as_str()),
+                ..definition
+            }));
+        }
+        let (_, mut policy) = parse_with_config(&policy_file.source, self.config)
+            .map_err(|e| LoadError::Parse(render_parse_error(&e, &policy_file.source, &policy_file.path)))?;
+        definitions.append(&mut policy.definitions);
+
+        // definitions are referred to by name, so each name can only mean one thing
+        let mut seen: HashMap<&str, (&str, Location)> = HashMap::new();
+        for definition in &definitions {
+            let file = definition.imported_from.unwrap_or(&policy_file.path);
+            let name = definition.variable.name;
+            if let Some((first_file, first_location)) = seen.get(name) {
+                return Err(LoadError::Collision {
+                    name: name.to_string(),
+                    first: (first_file.to_string(), *first_location),
+                    second: (file.to_string(), definition.variable.location),
+                });
+            }
+            seen.insert(name, (file, definition.variable.location));
+        }
+
+        policy.definitions = definitions;
+        Ok(policy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DEFAULT_CONFIG;
+
+    // A fresh directory holding `files`, each a path relative to the directory and its source
+    fn write_files(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("loader-{test}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        for (path, source) in files {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, source).unwrap();
+        }
+        dir
+    }
+
+    fn definition(name: &str, marker: &str) -> String {
+        format!("Definitions:\n1. \"{name}\" is each \"n\" marked {marker} where:\n    A. \"n\" is marked {marker}\n")
+    }
+
+    const BODY: &str = "Always:\n1. For each \"a\" marked x:\n    A. \"a\" is marked y\n";
+
+    fn path(dir: &Path, file: &str) -> String {
+        dir.join(file).to_string_lossy().into_owned()
+    }
+
+    #[test]
+    pub fn test_diamond_import() {
+        let shared = definition("shared", "s");
+        let left = format!("Using definitions from \"shared.txt\"\n{}", definition("left", "l"));
+        let right = format!("Using definitions from \"shared.txt\"\n{}", definition("right", "r"));
+        let policy = format!("Using definitions from \"left.txt\"\nUsing definitions from \"right.txt\"\n{BODY}");
+        let dir = write_files("diamond", &[
+            ("shared.txt", &shared),
+            ("left.txt", &left),
+            ("right.txt", &right),
+            ("policy.txt", &policy),
+        ]);
+
+        let sources = Sources::load(&path(&dir, "policy.txt"), &DEFAULT_CONFIG).unwrap();
+        // the shared file is only loaded once, before both files that import it
+        let files: Vec<&str> = sources.files.iter().map(|file| file.path.rsplit('/').next().unwrap()).collect();
+        assert_eq!(files, ["shared.txt", "left.txt", "right.txt", "policy.txt"]);
+
+        let policy = sources.parse().unwrap();
+        let names: Vec<&str> = policy.definitions.iter().map(|definition| definition.variable.name).collect();
+        assert_eq!(names, ["shared", "left", "right"]);
+        assert!(policy.definitions[0].imported_from.unwrap().ends_with("shared.txt"));
+    }
+
+    #[test]
+    pub fn test_import_cycle() {
+        let a = format!("Using definitions from \"b.txt\"\n{}", definition("a", "a"));
+        let b = format!("Using definitions from \"a.txt\"\n{}", definition("b", "b"));
+        let policy = format!("Using definitions from \"a.txt\"\n{BODY}");
+        let dir = write_files("cycle", &[("a.txt", &a), ("b.txt", &b), ("policy.txt", &policy)]);
+
+        match Sources::load(&path(&dir, "policy.txt"), &DEFAULT_CONFIG) {
+            Err(LoadError::Cycle { cycle, imported_at: (file, location) }) => {
+                let cycle: Vec<&str> = cycle.iter().map(|file| file.rsplit('/').next().unwrap()).collect();
+                assert_eq!(cycle, ["a.txt", "b.txt", "a.txt"]);
+                assert!(file.ends_with("b.txt"));
+                // at the imported path
+                assert_eq!(location, Location { line: 1, column: 25 });
+            },
+            other => panic!("expected a cycle, got {other:?}"),
+        }
+    }
+
+    #[test]
+    pub fn test_definition_collision() {
+        let storage = definition("stored", "s");
+        let policy = format!("Using definitions from \"storage.txt\"\n{}\n{BODY}", definition("stored", "t"));
+        let dir = write_files("collision", &[("storage.txt", &storage), ("policy.txt", &policy)]);
+
+        let sources = Sources::load(&path(&dir, "policy.txt"), &DEFAULT_CONFIG).unwrap();
+        match sources.parse() {
+            Err(LoadError::Collision { name, first: (first_file, first_location), second: (second_file, second_location) }) => {
+                assert_eq!(name, "stored");
+                assert!(first_file.ends_with("storage.txt"));
+                assert_eq!(first_location, Location { line: 2, column: 5 });
+                assert!(second_file.ends_with("policy.txt"));
+                assert_eq!(second_location, Location { line: 3, column: 5 });
+            },
+            other => panic!("expected a collision, got {other:?}"),
+        }
+    }
+
+    #[test]
+    pub fn test_missing_import() {
+        let policy = format!("Using definitions from \"nowhere.txt\"\n{BODY}");
+        let dir = write_files("missing", &[("policy.txt", &policy)]);
+
+        match Sources::load(&path(&dir, "policy.txt"), &DEFAULT_CONFIG) {
+            Err(LoadError::Read { path, imported_at: Some((file, location)), .. }) => {
+                assert!(path.ends_with("nowhere.txt"));
+                assert!(file.ends_with("policy.txt"));
+                assert_eq!(location, Location { line: 1, column: 25 });
+            },
+            other => panic!("expected a read error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    pub fn test_missing_policy_file() {
+        let dir = write_files("missing-policy", &[]);
+        match Sources::load(&path(&dir, "policy.txt"), &DEFAULT_CONFIG) {
+            Err(LoadError::Read { imported_at: None, .. }) => (),
+            other => panic!("expected a read error, got {other:?}"),
+        }
+    }
+}
@@ -1,30 +1,92 @@
-use nom::{error::context, branch::alt, sequence::{tuple, pair, preceded}, multi::many0, combinator::map};
-use crate::{Res, scope::scope, PolicyBody, clause::l1_clauses, common::*, relations::only_via_relation, ASTNode};
-
-fn only_vias<'a>(s: &'a str) -> Res<&str, ASTNode<'a>> { 
-    context(
-        "multiple only via",
-        map(
-            pair(
-                preceded(l1_bullet, map(only_via_relation, |rel| ASTNode::Relation(rel))), 
-                many0(tuple((operator, preceded(l1_bullet, map(only_via_relation, |rel| ASTNode::Relation(rel))))))
-            ),
-            join_nodes
-        )
-    )(s)
-}
+use nom::{
+    bytes::complete::{tag, take},
+    character::complete::space1,
+    combinator::{cut, map, peek},
+    error::context,
+    multi::many1,
+    sequence::{preceded, terminated, tuple},
+};
+use crate::{Res, Span, scope::scope, PolicyBody, clause::clauses, common::*};
 
-pub fn parse_policy_body<'a>(s: &'a str) -> Res<&str, PolicyBody<'a>> {
-    let mut combinator = context(
-        "policy body", 
-        tuple((scope, alt((only_vias, l1_clauses))))
-    );
-    let (remainder, (scope, body)) = combinator(s)?;
+fn scoped_clauses<'a>(s: Span<'a>) -> Res<Span<'a>, PolicyBody<'a>> {
+    let (remainder, (scope, body)) = tuple((scope, |s| clauses(1, s)))(s)?;
     Ok((
         remainder,
         PolicyBody {
+            name: None,
             scope,
             body
         }
     ))
-}
\ No newline at end of file
+}
+
+// Policy "community deletion":
+// Always:
+// 1. ...
+// Returns where the name starts along with the policy
+fn named_policy_body<'a>(s: Span<'a>) -> Res<Span<'a>, (Span<'a>, PolicyBody<'a>)> {
+    let mut combinator = context(
+        "named policy",
+        tuple((
+            preceded(tuple((multispace_comment0, tag("Policy"), space1)), peek(take(0usize))),
+            terminated(cut(variable), cut(colon)),
+            cut(scoped_clauses)
+        ))
+    );
+    let (remainder, (start, name, body)) = combinator(s)?;
+    Ok((remainder, (start, PolicyBody { name: Some(name), ..body })))
+}
+
+// Either a single policy, or several named ones.
+pub fn parse_policy_bodies<'a>(s: Span<'a>) -> Res<Span<'a>, Vec<PolicyBody<'a>>> {
+    let mut combinator = context("policy body", many1(named_policy_body));
+    let (remainder, named) = match combinator(s) {
+        Err(nom::Err::Error(_)) => {
+            return context("policy body", map(scoped_clauses, |body| vec![body]))(s);
+        },
+        res => res?,
+    };
+    // each named policy is reported separately, so their names must tell them apart
+    for (idx, (start, body)) in named.iter().enumerate() {
+        if named[..idx].iter().any(|(_, earlier)| earlier.name.map(|v| v.name) == body.name.map(|v| v.name)) {
+            return Err(failure(*start, "duplicate policy name"));
+        }
+    }
+    Ok((remainder, named.into_iter().map(|(_, body)| body).collect()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{common::parse_failure, parse, Location, PolicyScope};
+
+    #[test]
+    pub fn test_named_policies() {
+        let policy = "Policy \"community deletion\":\nAlways:\n1. For each \"a\" marked x:\n    A. \"a\" is marked y\n\n# instance rules\nPolicy \"instance ban\":\nSometimes:\n1. For each \"b\" marked x:\n    A. \"b\" is marked z";
+        let (_, parsed) = parse(policy).unwrap();
+        let names: Vec<_> = parsed.bodies.iter().map(|body| body.name.map(|name| (name.name, name.location))).collect();
+        assert_eq!(
+            names,
+            vec![
+                Some(("community deletion", Location { line: 1, column: 9 })),
+                Some(("instance ban", Location { line: 7, column: 9 })),
+            ]
+        );
+        assert_eq!(parsed.bodies[1].scope, PolicyScope::Sometimes);
+
+        let unnamed = "Always:\n1. For each \"a\" marked x:\n    A. \"a\" is marked y";
+        assert_eq!(parse(unnamed).unwrap().1.bodies[0].name, None);
+    }
+
+    #[test]
+    pub fn test_malformed_named_policy() {
+        let duplicate = "Policy \"deletion\":\nAlways:\n1. For each \"a\" marked x:\n    A. \"a\" is marked y\n\nPolicy \"deletion\":\nAlways:\n1. For each \"b\" marked x:\n    A. \"b\" is marked z";
+        assert_eq!(parse_failure(duplicate), Some(("duplicate policy name", Location { line: 6, column: 8 })));
+
+        let unquoted = "Policy deletion:\nAlways:\n1. For each \"a\" marked x:\n    A. \"a\" is marked y";
+        assert!(parse(unquoted).is_err());
+
+        // once one policy is named, they all are
+        let mixed = "Policy \"deletion\":\nAlways:\n1. For each \"a\" marked x:\n    A. \"a\" is marked y\n\nAlways:\n1. For each \"b\" marked x:\n    A. \"b\" is marked z";
+        assert!(parse(mixed).is_err());
+    }
+}
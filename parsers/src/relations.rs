@@ -2,155 +2,204 @@ use nom::{
     branch::alt,
     bytes::complete::tag,
     error::context,
-    sequence::{separated_pair, terminated, tuple, delimited, preceded, pair}, character::complete::space1, combinator::map, multi::many0,
+    sequence::{tuple, delimited, preceded, pair, terminated}, character::complete::{space0, space1}, combinator::{map, cut, opt, peek, value},
 };
 
 use crate::{
-    ASTNode, Res, common::*, variable_intro::{variable_intro, variable_marked, variable_def}, Relation,
+    ASTNode, EdgeSelection, Res, Span, common::*, variable_intro::{variable_intro, variable_marked, variable_def}, Relation, Variable,
 };
 
-// this is flows_to(EdgeSelection::DataAndControl)
-fn influences_relation<'a>(s: &'a str) -> Res<&str, Relation<'a>> {
+// Each relation is a subject variable followed by a predicate.
+// The predicate parsers take the subject separately so that clause intros can reuse them,
+// e.g. in "For each "a" that goes to "b"", the subject of "goes to" is "a".
+
+// (data only), (via control) or (data or control), written between a flow relation's keyword and its object
+fn edge_selection<'a>(s: Span<'a>) -> Res<Span<'a>, EdgeSelection> {
+    context(
+        "edge selection",
+        preceded(
+            tuple((space0, tag("("))),
+            cut(terminated(
+                alt((
+                    value(EdgeSelection::Data, tag("data only")),
+                    value(EdgeSelection::Control, tag("via control")),
+                    value(EdgeSelection::DataAndControl, tag("data or control")),
+                )),
+                tag(")")
+            ))
+        )
+    )(s)
+}
+
+// flows_to over `edges`, which are data and control edges unless the relation picks others
+fn influences_relation<'a>(subject: Variable<'a>, s: Span<'a>) -> Res<Span<'a>, Relation<'a>> {
     let mut combinator = context(
         "influences relation",
-        separated_pair(
-            variable, 
+        preceded(
             tuple((tag("influences"), space1)),
-            variable
+            cut(pair(opt(edge_selection), variable))
         )
     );
-    let (remainder, (var1, var2)) = combinator(s)?;
+    let (remainder, (edges, object)) = combinator(s)?;
 
     Ok((
         remainder,
-        Relation::Influences((var1, var2))
+        Relation::Influences((subject, object, edges.unwrap_or(EdgeSelection::DataAndControl)))
     ))
 }
 
-// this is flows_to(EdgeSelection::Data)
-fn goes_to_relation<'a>(s: &'a str) -> Res<&str, Relation<'a>> {
+// flows_to over `edges`, which are data edges unless the relation picks others
+fn goes_to_relation<'a>(subject: Variable<'a>, s: Span<'a>) -> Res<Span<'a>, Relation<'a>> {
     let mut combinator = context(
         "goes to relation", 
-        separated_pair(
-            variable, 
+        preceded(
             tuple((tag("goes to"), space1)),
-            variable
+            cut(pair(opt(edge_selection), variable))
         )
     );
-    let (remainder, (var1, var2)) = combinator(s)?;
+    let (remainder, (edges, object)) = combinator(s)?;
 
     Ok((
         remainder,
-        Relation::FlowsTo((var1, var2))
+        Relation::FlowsTo((subject, object, edges.unwrap_or(EdgeSelection::Data)))
     ))
 }
 
-fn does_not_go_to_relation<'a>(s: &'a str) -> Res<&str, Relation<'a>> {
+fn does_not_go_to_relation<'a>(subject: Variable<'a>, s: Span<'a>) -> Res<Span<'a>, Relation<'a>> {
     let mut combinator = context(
         "does not go to relation", 
-        separated_pair(
-            variable, 
+        preceded(
             tag("does not go to"), 
-            variable
+            cut(pair(opt(edge_selection), variable))
         )
     );
-    let (remainder, (var1, var2)) = combinator(s)?;
+    let (remainder, (edges, object)) = combinator(s)?;
 
     Ok((
         remainder,
-        Relation::NoFlowsTo((var1, var2))
+        Relation::NoFlowsTo((subject, object, edges.unwrap_or(EdgeSelection::Data)))
     ))
 }
 
-fn operation_associated_with_relation<'a>(s: &'a str) -> Res<&str, Relation<'a>> {
+fn operation_associated_with_relation<'a>(subject: Variable<'a>, s: Span<'a>) -> Res<Span<'a>, Relation<'a>> {
     let mut combinator = context(
         "operation associated with relation",
-        separated_pair(
-            variable, 
+        preceded(
             tag("goes to the operation associated with"), 
-            variable
+            cut(variable)
         )
     );
-    let (remainder, (var1, var2)) = combinator(s)?;
+    let (remainder, object) = combinator(s)?;
 
     Ok((
         remainder,
-        Relation::AssociatedCallSite((var1, var2))
+        Relation::AssociatedCallSite((subject, object))
     ))
 }
 
-fn affects_whether_relation<'a>(s: &'a str) -> Res<&str, Relation<'a>> {
+fn affects_whether_relation<'a>(subject: Variable<'a>, s: Span<'a>) -> Res<Span<'a>, Relation<'a>> {
     let mut combinator = context(
         "affects whether relation",
-        tuple((
-            terminated(variable, tag("affects whether")),
-            terminated(variable, tag("happens"))
-        )),
+        delimited(
+            tag("affects whether"),
+            cut(variable),
+            cut(tag("happens"))
+        ),
     );
-    let (remainder, (var1, var2)) = combinator(s)?;
+    let (remainder, object) = combinator(s)?;
 
     Ok((
         remainder,
-        Relation::ControlFlow((var1, var2)),
+        Relation::ControlFlow((subject, object)),
     ))
 }
 
-fn does_not_affects_whether_relation<'a>(s: &'a str) -> Res<&str, Relation<'a>> {
+fn does_not_affects_whether_relation<'a>(subject: Variable<'a>, s: Span<'a>) -> Res<Span<'a>, Relation<'a>> {
     let mut combinator = context(
         "does not affects whether relation",
-        tuple((
-            terminated(variable, tag("does not affect whether")),
-            terminated(variable, tag("happens"))
-        )),
+        delimited(
+            tag("does not affect whether"),
+            cut(variable),
+            cut(tag("happens"))
+        ),
+    );
+    let (remainder, object) = combinator(s)?;
+
+    Ok((
+        remainder,
+        Relation::NoControlFlow((subject, object))
+    ))
+}
+
+fn happens_before_relation<'a>(subject: Variable<'a>, s: Span<'a>) -> Res<Span<'a>, Relation<'a>> {
+    let mut combinator = context(
+        "happens before relation",
+        preceded(
+            tuple((tag("happens before"), space1)),
+            cut(variable)
+        )
+    );
+    let (remainder, object) = combinator(s)?;
+
+    Ok((
+        remainder,
+        Relation::HappensBefore((subject, object))
+    ))
+}
+
+fn happens_after_relation<'a>(subject: Variable<'a>, s: Span<'a>) -> Res<Span<'a>, Relation<'a>> {
+    let mut combinator = context(
+        "happens after relation",
+        preceded(
+            tuple((tag("happens after"), space1)),
+            cut(variable)
+        )
     );
-    let (remainder, (var1, var2)) = combinator(s)?;
+    let (remainder, object) = combinator(s)?;
 
     Ok((
         remainder,
-        Relation::NoControlFlow((var1, var2))
+        Relation::HappensAfter((subject, object))
     ))
 }
 
-fn is_marked_relation<'a>(s: &'a str) -> Res<&str, Relation<'a>> {
+fn is_marked_relation<'a>(subject: Variable<'a>, s: Span<'a>) -> Res<Span<'a>, Relation<'a>> {
     let mut combinator = context(
         "is marked relation",
-        separated_pair(
-            variable,
+        preceded(
             tag("is marked"),
-            marker,
+            cut(marker),
         )
     );
-    let (remainder, (var, marker)) = combinator(s)?;
+    let (remainder, marker) = combinator(s)?;
 
     Ok((
         remainder,
-        Relation::IsMarked((var, marker))
+        Relation::IsMarked((subject, marker))
     ))
 }
 
-fn is_not_marked_relation<'a>(s: &'a str) -> Res<&str, Relation<'a>> {
+fn is_not_marked_relation<'a>(subject: Variable<'a>, s: Span<'a>) -> Res<Span<'a>, Relation<'a>> {
     let mut combinator = context(
-        "is marked relation",
-        separated_pair(
-            variable,
+        "is not marked relation",
+        preceded(
             tag("is not marked"),
-            marker,
+            cut(marker),
         )
     );
-    let (remainder, (var, marker)) = combinator(s)?;
+    let (remainder, marker) = combinator(s)?;
 
     Ok((
         remainder,
-        Relation::IsNotMarked((var, marker)),
+        Relation::IsNotMarked((subject, marker)),
     ))
 }
 
-pub fn only_via_relation<'a>(s: &'a str) -> Res<&str, Relation<'a>> {
+pub fn only_via_relation<'a>(s: Span<'a>) -> Res<Span<'a>, Relation<'a>> {
     let mut combinator = context(
         "only via relation",
         tuple((
-            // these are only allowed to be present at the top level, hence the L1 bullet restriction
-            delimited(tuple((l1_bullet, tag("Each"), space1)), variable_intro, tag("goes to a")),
+            delimited(tuple((tag("Each"), space1)), variable_intro, tag("goes to a")),
             alt((variable_marked, variable_def)),
             preceded(
                 tag("only via a"),
@@ -162,81 +211,144 @@ pub fn only_via_relation<'a>(s: &'a str) -> Res<&str, Relation<'a>> {
 
     Ok((
         remainder,
-        Relation::OnlyVia((src, dest, checkpoint))
+        Relation::OnlyVia(Box::new((src, dest, checkpoint)))
     ))
 }
 
-pub fn relation<'a>(s: &'a str) -> Res<&str, Relation<'a>> {
+// Everything a relation can say about its subject
+pub fn predicate<'a>(subject: Variable<'a>, s: Span<'a>) -> Res<Span<'a>, Relation<'a>> {
     context(
-        "relation",
+        "predicate",
         alt((
-            // must try only via before goes to since goes to will partially consume an only via
-            only_via_relation,
-            goes_to_relation,
-            does_not_go_to_relation,
-            affects_whether_relation,
-            does_not_affects_whether_relation,
-            is_marked_relation,
-            is_not_marked_relation,
-            influences_relation,
-            operation_associated_with_relation,
+            // once a predicate's keyword matches, the rest of it must follow,
+            // so the operation associated with must come before goes to, which is its prefix
+            |s| operation_associated_with_relation(subject, s),
+            |s| goes_to_relation(subject, s),
+            |s| does_not_go_to_relation(subject, s),
+            |s| affects_whether_relation(subject, s),
+            |s| does_not_affects_whether_relation(subject, s),
+            |s| happens_before_relation(subject, s),
+            |s| happens_after_relation(subject, s),
+            |s| is_marked_relation(subject, s),
+            |s| is_not_marked_relation(subject, s),
+            |s| influences_relation(subject, s),
         ))
     )(s)
 }
 
-pub fn l2_relations<'a>(s: &'a str) -> Res<&str, ASTNode<'a>> {
-    context(
-        "l2 relations",
-        map(
-            pair(
-                preceded(l2_bullet, map(relation, |rel| ASTNode::Relation(rel))), 
-                many0(tuple((operator, (preceded(l2_bullet, map(relation, |rel| ASTNode::Relation(rel)))))))
-            ),
-            join_nodes
-        )
-    )(s)
+fn subject_relation<'a>(s: Span<'a>) -> Res<Span<'a>, Relation<'a>> {
+    let (remainder, subject) = variable(s)?;
+    predicate(subject, remainder)
+}
+
+pub fn relation<'a>(s: Span<'a>) -> Res<Span<'a>, Relation<'a>> {
+    context("relation", subject_relation)(s)
 }
 
-pub fn l3_relations<'a>(s: &'a str) -> Res<&str, ASTNode<'a>> {
+// A filter on a variable being introduced, e.g. "that goes to "b" and is not marked c".
+// The introduced variable is the subject of every predicate in the filter.
+pub fn filter<'a>(subject: Variable<'a>, s: Span<'a>) -> Res<Span<'a>, ASTNode<'a>> {
+    let that = || tuple((space0, tag("that"), space1));
+    let filter_predicate = move |s| map(|s| predicate(subject, s), ASTNode::Relation)(s);
     context(
-        "l3 relations",
-        map(
-            pair(
-                preceded(l3_bullet, map(relation, |rel| ASTNode::Relation(rel))), 
-                many0(tuple((operator, (preceded(l3_bullet, map(relation, |rel| ASTNode::Relation(rel)))))))
-            ),
-            join_nodes
+        "filter",
+        joined(
+            // "that" can only start a filter, so a predicate must follow it
+            preceded(that(), cut(filter_predicate)),
+            alt((preceded(that(), cut(filter_predicate)), filter_predicate))
         )
     )(s)
 }
 
-pub fn l4_relations<'a>(s: &'a str) -> Res<&str, ASTNode<'a>> {
-    context(
-        "l4 relations",
-        map(
-            pair(
-                preceded(l4_bullet, map(relation, |rel| ASTNode::Relation(rel))), 
-                many0(tuple((operator, (preceded(l4_bullet, map(relation, |rel| ASTNode::Relation(rel)))))))
-            ),
-            join_nodes
-        )
+// A relation always starts with a quoted variable and a clause never does,
+// so once we see one after a bullet, commit to parsing a relation.
+pub fn bulleted_relation<'a>(level: usize, s: Span<'a>) -> Res<Span<'a>, (Bullet<'a>, ASTNode<'a>)> {
+    pair(
+        terminated(|s| bullet(level, s), peek(tag("\""))),
+        cut(map(relation, ASTNode::Relation))
     )(s)
 }
 
-pub fn l5_relations<'a>(s: &'a str) -> Res<&str, ASTNode<'a>> {
-    context(
-        "l5 relations",
-        map(
-            pair(
-                preceded(l5_bullet, map(relation, |rel| ASTNode::Relation(rel))), 
-                many0(tuple((operator, (preceded(l5_bullet, map(relation, |rel| ASTNode::Relation(rel)))))))
-            ),
-            join_nodes
-        )
+// Only via relations are only allowed at the top level, hence the L1 bullet restriction
+pub fn bulleted_only_via_relation<'a>(s: Span<'a>) -> Res<Span<'a>, (Bullet<'a>, ASTNode<'a>)> {
+    pair(
+        |s| bullet(1, s),
+        map(only_via_relation, ASTNode::Relation)
     )(s)
 }
 
 
+#[cfg(test)]
+mod tests {
+    use nom::combinator::all_consuming;
+
+    use super::*;
+    use crate::DEFAULT_CONFIG;
+
+    fn parsed(s: &str) -> Option<Relation<'_>> {
+        all_consuming(relation)(Span::new_extra(s, &DEFAULT_CONFIG)).ok().map(|(_, relation)| relation)
+    }
+
+    // The relation's variables and marker, by name
+    fn names<'a>(relation: &Relation<'a>) -> Vec<&'a str> {
+        match relation {
+            Relation::Influences((src, dest, _))
+            | Relation::FlowsTo((src, dest, _))
+            | Relation::NoFlowsTo((src, dest, _))
+            | Relation::ControlFlow((src, dest))
+            | Relation::NoControlFlow((src, dest))
+            | Relation::AssociatedCallSite((src, dest))
+            | Relation::HappensBefore((src, dest))
+            | Relation::HappensAfter((src, dest)) => vec![src.name, dest.name],
+            Relation::IsMarked((var, marker)) | Relation::IsNotMarked((var, marker)) => vec![var.name, marker.name],
+            Relation::OnlyVia(_) => vec![],
+        }
+    }
+
+    #[test]
+    pub fn test_relation() {
+        let cases = [
+            ("\"a\" goes to \"b\"", "FlowsTo"),
+            ("\"a\" does not go to \"b\"", "NoFlowsTo"),
+            ("\"a\" influences \"b\"", "Influences"),
+            ("\"a\" affects whether \"b\" happens", "ControlFlow"),
+            ("\"a\" does not affect whether \"b\" happens", "NoControlFlow"),
+            ("\"a\" goes to the operation associated with \"b\"", "AssociatedCallSite"),
+            ("\"a\" happens before \"b\"", "HappensBefore"),
+            ("\"a\" happens after \"b\"", "HappensAfter"),
+            ("\"a\" is marked b", "IsMarked"),
+            ("\"a\" is not marked b", "IsNotMarked"),
+        ];
+        for (s, kind) in cases {
+            let relation = parsed(s).unwrap_or_else(|| panic!("couldn't parse {s}"));
+            assert!(format!("{relation:?}").starts_with(kind), "{s} parsed as {relation:?}");
+            assert_eq!(names(&relation), vec!["a", "b"]);
+        }
+    }
+
+    #[test]
+    pub fn test_edge_selection() {
+        let edges = |s| match parsed(s) {
+            Some(Relation::FlowsTo((_, _, edges)) | Relation::Influences((_, _, edges))) => Some(edges),
+            _ => None,
+        };
+        assert_eq!(edges("\"a\" goes to \"b\""), Some(EdgeSelection::Data));
+        assert_eq!(edges("\"a\" goes to (via control) \"b\""), Some(EdgeSelection::Control));
+        assert_eq!(edges("\"a\" goes to (data or control) \"b\""), Some(EdgeSelection::DataAndControl));
+        assert_eq!(edges("\"a\" influences \"b\""), Some(EdgeSelection::DataAndControl));
+        assert_eq!(edges("\"a\" influences (data only) \"b\""), Some(EdgeSelection::Data));
+        assert_eq!(edges("\"a\" goes to (everything) \"b\""), None);
+    }
+
+    #[test]
+    pub fn test_malformed_relation() {
+        assert_eq!(parsed("\"a\" goes to b"), None);
+        assert_eq!(parsed("\"a\" affects whether \"b\""), None);
+        assert_eq!(parsed("\"a\" is marked"), None);
+        assert_eq!(parsed("a goes to \"b\""), None);
+    }
+}
+
 /*
 #[cfg(test)]
 mod tests {
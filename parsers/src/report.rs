@@ -0,0 +1,250 @@
+use nom::error::{VerboseError, VerboseErrorKind};
+
+use crate::{lint::Warning, Location, Span};
+
+// What the parser was looking for when a low-level context failed.
+// These name the phrase the policy author needs to write.
+fn expected_phrase(context: &str) -> Option<&'static str> {
+    let phrase = match context {
+        "colon" => "`:`",
+        "then" => "`then:` after If-condition",
+        "where" => "`where:` after the variable",
+        "is each" => "`is each` after the name of the definition",
+        "operator" => "`and` or `or`",
+        "bullet" => "a bullet, like `1.` or `A.`",
+        "identifier" => "a name made of letters, digits and underscores, like `sha256_hash`",
+        "marker" => "a marker name, like `db_write`",
+        "quoted name" => "a name that fits on one line, without spaces around it",
+        "variable" => "a variable name in quotes, like \"data\"",
+        "variable intro" => "a variable, like \"data\" marked sensitive",
+        "relation" => "a relation, like \"a\" goes to \"b\"",
+        "edge selection" => "`(data only)`, `(via control)` or `(data or control)`",
+        "predicate" => "what the variable does, like `goes to \"b\"`",
+        "scope" => "`Always:`, `Sometimes:` or `In <controller>:`",
+        "controller names" => "controller names separated by commas, like `gdpr_deletes, account_purge`",
+        "import path" => "a path in quotes, like \"common/storage.txt\"",
+        "number" => "a number, like `2` or `one`",
+        "comma" => "`,` before what must hold for each",
+        "pattern" => "a pattern in quotes, like \"*_deletes\"",
+        _ => return None,
+    };
+    Some(phrase)
+}
+
+// Mistakes that the parser recognized, rather than phrases it was looking for.
+fn problem_phrase(context: &str) -> Option<&'static str> {
+    let phrase = match context {
+        "bullet label" => "not a valid bullet label; numbering starts at 1, and roman numerals are written like `iv`, not `iiii`",
+        "bullet out of sequence" => "bullet out of sequence; each list must start at 1, A, a or i and count up without skipping or repeating",
+        "misaligned bullet" => "bullet is not lined up with the first bullet of its list",
+        "bullet not indented" => "nested bullet must be indented further than the bullet it belongs to",
+        "duplicate policy name" => "another policy already has this name",
+        "missing where condition" => "expected `where` and what must hold, like `where \"c\" goes to \"sink\"`",
+        "misplaced otherwise" => "`Otherwise:` can only follow the body of an `If` or `Unless` clause",
+        "misaligned otherwise" => "`Otherwise:` must line up with the bullet of its `If` or `Unless` clause",
+        "mixed operators" => "cannot mix `and` and `or` at the same level; use the same operator throughout",
+        _ => return None,
+    };
+    Some(phrase)
+}
+
+// The larger piece of policy that a failure happened inside of.
+fn enclosing_phrase(context: &str) -> Option<&'static str> {
+    let phrase = match context {
+        "conditional" => "an If-condition",
+        "unless" => "an `Unless` condition",
+        "there is condition" => "a `there is a` condition",
+        "for each condition" => "a `for each` condition",
+        "for each" => "a `For each` clause",
+        "there is" => "a `There is a` clause",
+        "there is no" => "a `There is no` clause",
+        "counted" => "a counting clause, like `There is exactly one`",
+        "filter" => "a `that` filter",
+        "only via relation" => "an `only via` relation",
+        "definition" => "a definition",
+        "definitions" => "the definitions",
+        "in ctrler" => "an `In <controller>:` scope",
+        "policy body" => "the policy body",
+        "named policy" => "a `Policy` section",
+        "import" => "an import",
+        _ => return None,
+    };
+    Some(phrase)
+}
+
+// Render the line of source containing `location`, with a caret under the offending column.
+fn snippet(source: &str, location: Location) -> String {
+    let line_number = location.line;
+    let line = source.lines().nth(line_number as usize - 1).unwrap_or("");
+    let column = location.column;
+    let gutter = " ".repeat(line_number.to_string().len());
+    // keep tabs so the caret lines up with the source as the author sees it
+    let padding: String = line
+        .chars()
+        .take(column - 1)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    format!("{gutter} |\n{line_number} | {line}\n{gutter} | {padding}^")
+}
+
+// Punctuation that many parsers share; a more specific phrase from an enclosing parser is more helpful.
+const GENERIC_CONTEXTS: [&str; 2] = ["colon", "operator"];
+
+// Turn the error from `parse` into a report a policy author can read, e.g.
+//
+// error: expected `then:` after If-condition
+//  --> policy.txt:4:42
+//   |
+// 4 |         a. If "data" goes to "write" then
+//   |                                          ^
+//   = while parsing an If-condition
+pub fn render_error(err: &VerboseError<Span>, source: &str, path: &str) -> String {
+    // nom only keeps the last alternative that failed, so the most useful error
+    // is whichever one got furthest into the policy before failing.
+    // Ties go to the innermost error, which comes first.
+    let Some(deepest) = err.errors
+        .iter()
+        .enumerate()
+        .max_by_key(|(idx, (span, _))| (span.location_offset(), std::cmp::Reverse(*idx)))
+        .map(|(idx, _)| idx) else {
+        return format!("error: could not parse {path}");
+    };
+    let (span, kind) = &err.errors[deepest];
+
+    // contexts enclosing the failure, innermost first
+    let contexts: Vec<(&Span, &str)> = err.errors[deepest..]
+        .iter()
+        .filter_map(|(span, kind)| match kind {
+            VerboseErrorKind::Context(context) => Some((span, *context)),
+            _ => None,
+        })
+        .collect();
+    let same_line = |context_span: &Span| context_span.location_line() == span.location_line();
+    let specific = contexts
+        .iter()
+        .filter(|(context_span, context)| same_line(context_span) && !GENERIC_CONTEXTS.contains(context))
+        .find_map(|(_, context)| expected_phrase(context));
+    let phrase = specific.or_else(|| contexts.iter().find_map(|(_, context)| expected_phrase(context)));
+    let problem = match kind {
+        VerboseErrorKind::Context(context) => problem_phrase(context),
+        _ => None,
+    };
+    let message = match (problem, phrase, kind) {
+        (Some(problem), _, _) => problem.to_string(),
+        (None, Some(phrase), _) => format!("expected {phrase}"),
+        (None, None, VerboseErrorKind::Char(c)) => format!("expected `{c}`"),
+        (None, None, _) => "could not make sense of the policy from here on".to_string(),
+    };
+
+    let gutter = " ".repeat(span.location_line().to_string().len());
+    let mut report = format!(
+        "error: {message}\n{gutter}--> {path}:{}:{}\n{}",
+        span.location_line(),
+        span.get_utf8_column(),
+        snippet(source, (*span).into())
+    );
+    // Name the innermost clause that the failure is inside of.
+    // Clauses that failed right where they started only tell us which alternative nom tried last.
+    let enclosing = contexts
+        .iter()
+        .filter(|(context_span, _)| context_span.location_offset() < span.location_offset())
+        .find_map(|(_, context)| enclosing_phrase(context));
+    if let Some(phrase) = enclosing {
+        report.push_str(&format!("\n{gutter} = while parsing {phrase}"));
+    }
+    report
+}
+
+pub fn render_parse_error(err: &nom::Err<VerboseError<Span>>, source: &str, path: &str) -> String {
+    match err {
+        nom::Err::Error(e) | nom::Err::Failure(e) => render_error(e, source, path),
+        // all of our parsers are complete, so they never ask for more input
+        nom::Err::Incomplete(_) => format!("error: {path} ended unexpectedly"),
+    }
+}
+
+pub fn render_warning(warning: &Warning, source: &str, path: &str) -> String {
+    let gutter = " ".repeat(warning.location.line.to_string().len());
+    format!(
+        "warning: {}\n{gutter}--> {path}:{}\n{}",
+        warning.message,
+        warning.location,
+        snippet(source, warning.location)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use nom::Slice;
+
+    use super::*;
+    use crate::{parse, DEFAULT_CONFIG};
+
+    fn report(policy: &str) -> String {
+        render_parse_error(&parse(policy).unwrap_err(), policy, "policy.txt")
+    }
+
+    #[test]
+    pub fn test_report() {
+        let policy = "Always:\n1. For each \"a\" marked x:\n    A. If \"a\" is marked y\n        a. \"a\" is marked z";
+        assert_eq!(
+            report(policy),
+            "error: expected `then:` after If-condition\n --> policy.txt:4:9\n  |\n4 |         a. \"a\" is marked z\n  |         ^\n  = while parsing an If-condition"
+        );
+
+        let incomplete = nom::Err::Incomplete(nom::Needed::Unknown);
+        assert_eq!(render_parse_error(&incomplete, "", "policy.txt"), "error: policy.txt ended unexpectedly");
+    }
+
+    #[test]
+    pub fn test_caret_column() {
+        let policy = "Always:\n1. For each \"a\" marked x:\n\tA. There is a \"b\" marked y\n\t\ta. \"a\" goes to \"b\"";
+        assert_eq!(
+            report(policy),
+            "error: expected `where:` after the variable\n --> policy.txt:4:3\n  |\n4 | \t\ta. \"a\" goes to \"b\"\n  | \t\t^\n  = while parsing a `There is a` clause"
+        );
+    }
+
+    #[test]
+    pub fn test_deepest_context() {
+        let source = "Always:\n1. For each \"a\" marked x:";
+        let span = Span::new_extra(source, &DEFAULT_CONFIG);
+        let err = VerboseError {
+            errors: vec![
+                (span.slice(8..), VerboseErrorKind::Context("bullet")),
+                (span.slice(11..), VerboseErrorKind::Context("where")),
+                (span.slice(8..), VerboseErrorKind::Context("scope")),
+                (span.slice(11..), VerboseErrorKind::Context("variable")),
+            ],
+        };
+        let report = render_error(&err, source, "policy.txt");
+        assert!(report.starts_with("error: expected `where:` after the variable\n --> policy.txt:2:4\n"), "{report}");
+    }
+
+    #[test]
+    pub fn test_expected_phrase() {
+        let policy = "Always:\n1. For each \"a\" marked x:\n    A. \"a\" goes to (everything) \"b\"";
+        assert!(report(policy).starts_with("error: expected `(data only)`, `(via control)` or `(data or control)`\n"));
+
+        let source = "Always:";
+        let span = Span::new_extra(source, &DEFAULT_CONFIG);
+        let err = |kind| VerboseError { errors: vec![(span.slice(6..), kind), (span, VerboseErrorKind::Context("scope"))] };
+        // a problem the parser recognized beats what it was looking for
+        let problem = render_error(&err(VerboseErrorKind::Context("bullet not indented")), source, "policy.txt");
+        assert!(problem.starts_with("error: nested bullet must be indented further than the bullet it belongs to\n"));
+        let expected = render_error(&err(VerboseErrorKind::Char(':')), source, "policy.txt");
+        assert!(expected.starts_with("error: expected `Always:`, `Sometimes:` or `In <controller>:`\n"));
+        let nothing = render_error(&VerboseError { errors: vec![] }, source, "policy.txt");
+        assert_eq!(nothing, "error: could not parse policy.txt");
+    }
+
+    #[test]
+    pub fn test_warning() {
+        let source = "Always:\n1. For each \"a\" marked x:\n \tA. \"a\" is marked y";
+        let warning = Warning { location: Location { line: 3, column: 3 }, message: "indentation mixes tabs and spaces".to_string() };
+        assert_eq!(
+            render_warning(&warning, source, "policy.txt"),
+            "warning: indentation mixes tabs and spaces\n --> policy.txt:3:3\n  |\n3 |  \tA. \"a\" is marked y\n  |  \t^"
+        );
+    }
+}
@@ -0,0 +1,507 @@
+use std::fmt::Display;
+
+use crate::{ASTNode, Clause, ClauseIntro, Definition, Location, Policy, Relation, Variable, VariableIntro};
+
+// Checks that every variable a policy refers to is bound by an enclosing clause or definition.
+// Variables are bound by:
+//   - a definition, in its own filter, e.g. "s" in `"sens" is each "s" marked sensitive where:`
+//   - a `For each`, `There is`, `There is no` or counting clause, in its filter and body
+//   - a `there is a` in an If-condition, in the rest of the condition and in the body,
+//     unless it's under an `or`; see ClauseIntro::Conditional
+// Definition names are in scope everywhere; introducing one, as in `For each "sens":`, binds its name.
+// The variables bound inside a definition stay there: a "stored commit" is the commit itself,
+// and the "store" that witnesses it isn't exposed, since a commit can be stored in many places.
+// Referring to them elsewhere is reported along with what to write instead.
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Problem<'a> {
+    // referred to outside of any clause that binds it
+    Unbound(Variable<'a>),
+    // introduced as a definition's name, but there is no such definition
+    Undefined(Variable<'a>),
+    // bound again inside a clause that already binds it; the location is the earlier binding
+    Shadowed((Variable<'a>, Location)),
+    // bound with the same name as a definition, which hides the definition; the location is the definition's
+    ShadowsDefinition((Variable<'a>, Location)),
+    // a second definition with the same name; the location is the first one
+    Duplicate((Variable<'a>, Location)),
+    // the variable a definition declares, referred to outside of it; the second is the definition's name
+    DefinitionVariable((Variable<'a>, Variable<'a>)),
+    // a variable bound by a clause inside a definition, referred to outside of it;
+    // the second is where the definition binds it and the third is the definition's name
+    DefinitionWitness((Variable<'a>, Variable<'a>, Variable<'a>)),
+    // a definition's name, referred to inside that definition; the second is the variable it declares
+    OwnName((Variable<'a>, Variable<'a>)),
+    // a use of two templates at once, e.g. "a to b" for both "<x> to b" and "a to <y>"; then the two templates' names
+    Ambiguous((Variable<'a>, Variable<'a>, Variable<'a>)),
+    // a template use whose variable argument has the same name as a variable the template binds itself;
+    // then that variable and the template's name
+    Captured((Variable<'a>, Variable<'a>, Variable<'a>)),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ScopeError<'a> {
+    pub problem: Problem<'a>,
+    // the imported file the problem is in; None for the policy file itself
+    pub file: Option<&'a str>,
+}
+
+impl<'a> ScopeError<'a> {
+    pub fn location(&self) -> Location {
+        match &self.problem {
+            Problem::Unbound(var)
+            | Problem::Undefined(var)
+            | Problem::Shadowed((var, _))
+            | Problem::ShadowsDefinition((var, _))
+            | Problem::Duplicate((var, _))
+            | Problem::DefinitionVariable((var, _))
+            | Problem::DefinitionWitness((var, _, _))
+            | Problem::OwnName((var, _))
+            | Problem::Ambiguous((var, _, _))
+            | Problem::Captured((var, _, _)) => var.location,
+        }
+    }
+}
+
+impl<'a> Display for ScopeError<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.problem {
+            Problem::Unbound(var) => write!(f, "variable \"{}\" is not bound", var.name),
+            Problem::Undefined(var) => {
+                write!(f, "variable \"{}\" needs a marker; there is no definition for it", var.name)
+            },
+            Problem::Shadowed((var, earlier)) => {
+                write!(f, "variable \"{}\" is already bound at {earlier}; choose a different name", var.name)
+            },
+            Problem::ShadowsDefinition((var, definition)) => write!(
+                f,
+                "variable \"{}\" has the same name as the definition at {definition}; choose a different name",
+                var.name
+            ),
+            Problem::Duplicate((var, first)) => {
+                write!(f, "definition \"{}\" is already defined at {first}", var.name)
+            },
+            Problem::DefinitionVariable((var, definition)) => write!(
+                f,
+                "variable \"{}\" is only bound inside the definition of \"{}\"; refer to \"{}\" instead",
+                var.name, definition.name, definition.name
+            ),
+            Problem::DefinitionWitness((var, witness, definition)) => write!(
+                f,
+                "variable \"{}\" is only bound inside the definition of \"{}\", at {}; introduce it here with its own clause",
+                var.name, definition.name, witness.location
+            ),
+            Problem::OwnName((var, declared)) => write!(
+                f,
+                "\"{}\" is not bound inside its own definition; refer to its variable \"{}\" instead",
+                var.name, declared.name
+            ),
+            Problem::Ambiguous((var, first, second)) => write!(
+                f,
+                "\"{}\" could use the definition \"{}\" at {} or \"{}\" at {}; rename one of them",
+                var.name, first.name, first.location, second.name, second.location
+            ),
+            Problem::Captured((var, inner, template)) => write!(
+                f,
+                "\"{}\" passes variable \"{}\" to \"{}\", which binds its own \"{}\" at {}; rename one of them",
+                var.name, inner.name, template.name, inner.name, inner.location
+            ),
+        }
+    }
+}
+
+// The variables a template binds itself, which its arguments are substituted alongside
+fn template_bound_variables<'a>(template: &Definition<'a>) -> Vec<Variable<'a>> {
+    fn clause_variables<'a>(node: &ASTNode<'a>, found: &mut Vec<Variable<'a>>) {
+        match node {
+            ASTNode::Relation(_) => (),
+            ASTNode::And(obligation) | ASTNode::Or(obligation) | ASTNode::Conditional(obligation) => {
+                clause_variables(&obligation.src, found);
+                clause_variables(&obligation.dest, found);
+            },
+            ASTNode::Clause(clause) => {
+                match &clause.intro {
+                    ClauseIntro::ForEach((intro, filter))
+                    | ClauseIntro::ThereIs((intro, filter))
+                    | ClauseIntro::ThereIsNo((intro, filter))
+                    | ClauseIntro::Counted((_, intro, filter)) => {
+                        found.extend(intro.variable());
+                        if let Some(filter) = filter {
+                            clause_variables(filter, found);
+                        }
+                    },
+                    ClauseIntro::Conditional(condition) | ClauseIntro::Unless(condition) => {
+                        clause_variables(condition, found)
+                    },
+                }
+                clause_variables(&clause.body, found);
+                if let Some(otherwise) = &clause.otherwise {
+                    clause_variables(otherwise, found);
+                }
+            },
+        }
+    }
+    let mut found: Vec<Variable<'a>> = template.declaration.variable().into_iter().collect();
+    clause_variables(&template.filter, &mut found);
+    // a parameter like "<v>" is replaced by the argument, so it can't capture it
+    let parameters = template.parameters();
+    found.retain(|var| !parameters.contains(&var.name));
+    found
+}
+
+struct Resolver<'p, 'a> {
+    definitions: &'p [Definition<'a>],
+    // variables bound by enclosing clauses, innermost last
+    bound: Vec<Variable<'a>>,
+    // the file of whatever is being resolved
+    file: Option<&'a str>,
+    // the definition being resolved, if any
+    defining: Option<&'p Definition<'a>>,
+    // every variable bound inside a definition, along with that definition
+    inner: Vec<(Variable<'a>, &'p Definition<'a>)>,
+    errors: Vec<ScopeError<'a>>,
+}
+
+impl<'p, 'a> Resolver<'p, 'a> {
+    fn report(&mut self, problem: Problem<'a>) {
+        self.errors.push(ScopeError { problem, file: self.file });
+    }
+
+    // definitions without parameters; templates are found by instantiating them
+    fn definition(&self, name: &str) -> Option<&'p Definition<'a>> {
+        self.definitions.iter().find(|definition| definition.variable.name == name && definition.parameters().is_empty())
+    }
+
+    fn variable(&mut self, var: &Variable<'a>) {
+        if !self.bound.iter().any(|bound| bound.name == var.name) {
+            let problem = self.unbound(var);
+            self.report(problem);
+        }
+    }
+
+    // Unbound variables that are bound inside a definition get a hint about what to write instead.
+    // A definition whose name is in scope is the likeliest one to have been meant.
+    fn unbound(&self, var: &Variable<'a>) -> Problem<'a> {
+        if let Some(defining) = self.defining.filter(|defining| defining.variable.name == var.name) {
+            if let Some(declared) = defining.declaration.variable() {
+                return Problem::OwnName((*var, declared));
+            }
+        }
+        let in_scope = |definition: &Definition<'a>| self.bound.iter().any(|bound| bound.name == definition.variable.name);
+        let mut candidates = self.inner.iter().filter(|(inner, _)| inner.name == var.name);
+        let Some((inner, definition)) = candidates.clone().find(|(_, definition)| in_scope(definition)).or(candidates.next()) else {
+            return Problem::Unbound(*var);
+        };
+        if definition.declaration.variable().is_some_and(|declared| declared == *inner) {
+            Problem::DefinitionVariable((*var, definition.variable))
+        } else {
+            Problem::DefinitionWitness((*var, *inner, definition.variable))
+        }
+    }
+
+    // Check what an intro refers to, returning the variable it introduces
+    fn intro(&mut self, intro: &VariableIntro<'a>) -> Option<Variable<'a>> {
+        match intro {
+            VariableIntro::Variable(var) if self.definition(var.name).is_none() => {
+                let mut instances = self
+                    .definitions
+                    .iter()
+                    .filter_map(|template| Some((template, template.instantiate(var.name)?)));
+                match (instances.next(), instances.next()) {
+                    (Some((first, _)), Some((second, _))) => {
+                        self.report(Problem::Ambiguous((*var, first.variable, second.variable)))
+                    },
+                    (Some((template, instance)), None) => {
+                        let inner = template_bound_variables(template);
+                        // an argument is substituted everywhere its parameter appears; check it once
+                        let mut arguments = instance.variables;
+                        arguments.sort_unstable();
+                        arguments.dedup();
+                        for argument in arguments {
+                            // a template's variable arguments must be bound where it's used
+                            self.variable(&Variable { name: argument, location: var.location });
+                            // and can't share a name with the template's own variables, which would capture them
+                            if let Some(inner) = inner.iter().find(|inner| inner.name == argument) {
+                                self.report(Problem::Captured((*var, *inner, template.variable)));
+                            }
+                        }
+                    },
+                    // dependencies.rs reports the ones inside definitions, along with their other dependency problems
+                    (None, _) if self.defining.is_some() => (),
+                    (None, _) => self.report(Problem::Undefined(*var)),
+                }
+            },
+            VariableIntro::VariableSourceof((_, source_of)) => self.variable(source_of),
+            _ => (),
+        }
+        intro.variable()
+    }
+
+    fn bind(&mut self, intro: &VariableIntro<'a>, var: Variable<'a>) {
+        if let Some(earlier) = self.bound.iter().find(|bound| bound.name == var.name) {
+            let earlier = earlier.location;
+            self.report(Problem::Shadowed((var, earlier)));
+        } else if let Some(definition) = self.definition(var.name) {
+            // `For each "sens":` binds the definition's own name, which is how definitions are used
+            if !matches!(intro, VariableIntro::Variable(_)) {
+                let location = definition.variable.location;
+                self.report(Problem::ShadowsDefinition((var, location)));
+            }
+        }
+        if let Some(defining) = self.defining {
+            self.inner.push((var, defining));
+        }
+        self.bound.push(var);
+    }
+
+    // Resolve an intro and its filter, leaving the variable bound for the caller to unbind
+    fn filtered_intro(&mut self, intro: &VariableIntro<'a>, filter: &Option<ASTNode<'a>>) {
+        if let Some(var) = self.intro(intro) {
+            self.bind(intro, var);
+        }
+        if let Some(filter) = filter {
+            self.node(filter);
+        }
+    }
+
+    fn relation(&mut self, relation: &Relation<'a>) {
+        match relation {
+            Relation::Influences((src, dest, _))
+            | Relation::FlowsTo((src, dest, _))
+            | Relation::NoFlowsTo((src, dest, _)) => {
+                self.variable(src);
+                self.variable(dest);
+            },
+            Relation::ControlFlow((src, dest))
+            | Relation::NoControlFlow((src, dest))
+            | Relation::AssociatedCallSite((src, dest))
+            | Relation::HappensBefore((src, dest))
+            | Relation::HappensAfter((src, dest)) => {
+                self.variable(src);
+                self.variable(dest);
+            },
+            Relation::IsMarked((var, _)) | Relation::IsNotMarked((var, _)) => self.variable(var),
+            // each intro ranges over its own nodes without binding a variable
+            Relation::OnlyVia(intros) => {
+                let (src, dest, checkpoint) = &**intros;
+                self.intro(src);
+                self.intro(dest);
+                self.intro(checkpoint);
+            },
+        }
+    }
+
+    // The parts of an If-condition, in order. The variables of `there is a` parts stay bound for the body.
+    fn condition(&mut self, conjuncts: Vec<&ASTNode<'a>>) {
+        for conjunct in conjuncts {
+            match conjunct {
+                ASTNode::Clause(clause) => match &clause.intro {
+                    ClauseIntro::ThereIs((intro, filter)) => {
+                        self.filtered_intro(intro, filter);
+                        self.condition(clause.body.conjuncts());
+                    },
+                    _ => self.node(conjunct),
+                },
+                _ => self.node(conjunct),
+            }
+        }
+    }
+
+    fn clause(&mut self, clause: &Clause<'a>) {
+        let len = self.bound.len();
+        match &clause.intro {
+            ClauseIntro::ForEach((intro, filter))
+            | ClauseIntro::ThereIs((intro, filter))
+            | ClauseIntro::ThereIsNo((intro, filter))
+            | ClauseIntro::Counted((_, intro, filter)) => self.filtered_intro(intro, filter),
+            ClauseIntro::Conditional(condition) => self.condition(condition.conjuncts()),
+            ClauseIntro::Unless(condition) => self.node(condition),
+        }
+        self.node(&clause.body);
+        // the Otherwise: block only applies when the condition doesn't, so it can't see the condition's variables
+        self.bound.truncate(len);
+        if let Some(otherwise) = &clause.otherwise {
+            self.node(otherwise);
+        }
+    }
+
+    fn node(&mut self, node: &ASTNode<'a>) {
+        match node {
+            ASTNode::Relation(relation) => self.relation(relation),
+            ASTNode::And(obligation) | ASTNode::Or(obligation) | ASTNode::Conditional(obligation) => {
+                self.node(&obligation.src);
+                self.node(&obligation.dest);
+            },
+            ASTNode::Clause(clause) => self.clause(clause),
+        }
+    }
+
+    fn definitions(&mut self) {
+        for (idx, definition) in self.definitions.iter().enumerate() {
+            self.file = definition.imported_from;
+            let name = &definition.variable;
+            if let Some(first) = self.definitions[..idx].iter().find(|first| first.variable.name == name.name) {
+                let first = first.variable.location;
+                self.report(Problem::Duplicate((*name, first)));
+            }
+            // a template's parameters stand in for variables bound where it's used
+            self.bound = definition
+                .parameters()
+                .into_iter()
+                .map(|parameter| Variable { name: parameter, location: name.location })
+                .collect();
+            self.defining = Some(definition);
+            self.filtered_intro(&definition.declaration, &None);
+            self.node(&definition.filter);
+        }
+        self.defining = None;
+        self.file = None;
+        self.bound.clear();
+    }
+}
+
+// Every scoping mistake in the policy, in the order they appear: definitions first, then the bodies.
+pub fn resolve<'a>(policy: &Policy<'a>) -> Vec<ScopeError<'a>> {
+    let mut resolver = Resolver {
+        definitions: &policy.definitions,
+        bound: vec![],
+        file: None,
+        defining: None,
+        inner: vec![],
+        errors: vec![],
+    };
+    resolver.definitions();
+    for body in &policy.bodies {
+        resolver.node(&body.body);
+        resolver.bound.clear();
+    }
+    resolver.errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+
+    const DEFINITIONS: &str = "Definitions:\n1. \"sens\" is each \"s\" marked sensitive where:\n    A. There is a \"store\" marked store where:\n        a. \"s\" goes to \"store\"\n\n";
+
+    fn problems(policy: &str) -> Vec<Problem<'_>> {
+        let (_, policy) = parse(policy).unwrap();
+        resolve(&policy).into_iter().map(|error| error.problem).collect()
+    }
+
+    // The names of the variables that are reported unbound, in order
+    fn unbound(policy: &str) -> Vec<&str> {
+        problems(policy)
+            .into_iter()
+            .map(|problem| match problem {
+                Problem::Unbound(var) => var.name,
+                problem => panic!("expected only unbound variables, got {problem:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    pub fn test_unbound() {
+        let policy = "Always:\n1. For each \"a\" marked x:\n    A. \"a\" goes to \"b\"\nand\n2. For each \"c\" marked x:\n    A. \"a\" is marked y";
+        assert_eq!(unbound(policy), vec!["b", "a"]);
+
+        let nested = "Always:\n1. For each \"a\" marked x:\n    A. There is a \"b\" marked y where:\n        a. \"a\" goes to \"b\"\n    and\n    B. \"b\" is marked z";
+        assert_eq!(unbound(nested), vec!["b"]);
+    }
+
+    #[test]
+    pub fn test_shadowed() {
+        let policy = "Always:\n1. For each \"a\" marked x:\n    A. For each \"a\" marked y:\n        a. \"a\" is marked z";
+        let found = problems(policy);
+        assert!(matches!(found.as_slice(), [Problem::Shadowed((var, earlier))] if var.name == "a" && earlier.line == 2));
+
+        // siblings don't shadow each other
+        let siblings = "Always:\n1. For each \"a\" marked x:\n    A. \"a\" is marked y\nand\n2. For each \"a\" marked z:\n    A. \"a\" is marked y";
+        assert!(unbound(siblings).is_empty());
+    }
+
+    #[test]
+    pub fn test_shadows_definition() {
+        let policy = format!("{DEFINITIONS}Always:\n1. For each \"sens\" marked x:\n    A. \"sens\" is marked y");
+        let found = problems(&policy);
+        assert!(matches!(found.as_slice(), [Problem::ShadowsDefinition((var, definition))] if var.name == "sens" && definition.line == 2));
+
+        // introducing the definition itself is how it's used
+        let used = format!("{DEFINITIONS}Always:\n1. For each \"sens\":\n    A. \"sens\" is marked y");
+        assert!(unbound(&used).is_empty());
+    }
+
+    #[test]
+    pub fn test_duplicate_definition() {
+        let policy = "Definitions:\n1. \"sens\" is each \"s\" marked sensitive where:\n    A. \"s\" is marked x\n2. \"sens\" is each \"t\" marked secret where:\n    A. \"t\" is marked x\n\nAlways:\n1. For each \"sens\":\n    A. \"sens\" is marked y";
+        let found = problems(policy);
+        assert!(matches!(found.as_slice(), [Problem::Duplicate((var, first))] if var.name == "sens" && first.line == 2));
+    }
+
+    #[test]
+    pub fn test_definition_variables() {
+        let declared = format!("{DEFINITIONS}Always:\n1. For each \"sens\":\n    A. \"s\" is marked y");
+        let found = problems(&declared);
+        assert!(matches!(found.as_slice(), [Problem::DefinitionVariable((var, definition))] if var.name == "s" && definition.name == "sens"));
+
+        let witness = format!("{DEFINITIONS}Always:\n1. For each \"sens\":\n    A. \"sens\" goes to \"store\"");
+        let found = problems(&witness);
+        assert!(matches!(
+            found.as_slice(),
+            [Problem::DefinitionWitness((var, inner, definition))]
+                if var.name == "store" && inner.location.line == 3 && definition.name == "sens"
+        ));
+
+        let own_name = "Definitions:\n1. \"sens\" is each \"s\" marked sensitive where:\n    A. \"sens\" is marked x\n\nAlways:\n1. For each \"sens\":\n    A. \"sens\" is marked y";
+        let found = problems(own_name);
+        assert!(matches!(found.as_slice(), [Problem::OwnName((var, declared))] if var.name == "sens" && declared.name == "s"));
+    }
+
+    #[test]
+    pub fn test_there_is_in_condition() {
+        let condition = "Always:\n1. For each \"sink\" marked sink:\n    A. If there is a \"c\" marked check where \"c\" goes to \"sink\" and \"c\" is marked strong then:\n        a. \"c\" is marked x";
+        assert!(unbound(condition).is_empty());
+
+        // under an `or`, the condition can hold without "c"
+        let or = "Always:\n1. For each \"sink\" marked sink:\n    A. If \"sink\" is marked safe or there is a \"c\" marked check where \"c\" goes to \"sink\" then:\n        a. \"c\" is marked x";
+        assert_eq!(unbound(or), vec!["c"]);
+
+        let otherwise = "Always:\n1. For each \"sink\" marked sink:\n    A. If there is a \"c\" marked check where \"c\" goes to \"sink\" then:\n        a. \"c\" is marked x\n    Otherwise:\n        a. \"c\" is marked y";
+        assert_eq!(unbound(otherwise), vec!["c"]);
+
+        let unless = "Always:\n1. For each \"sink\" marked sink:\n    A. Unless there is a \"c\" marked check where \"c\" goes to \"sink\":\n        a. \"c\" is marked x";
+        assert_eq!(unbound(unless), vec!["c"]);
+
+        // the condition's variables don't outlive its clause
+        let after = "Always:\n1. For each \"sink\" marked sink:\n    A. If there is a \"c\" marked check where \"c\" goes to \"sink\" then:\n        a. \"c\" is marked x\n    and\n    B. \"c\" is marked y";
+        assert_eq!(unbound(after), vec!["c"]);
+    }
+
+    #[test]
+    pub fn test_template_uses() {
+        let template = "Definitions:\n1. \"flows to <v>\" is each \"x\" marked m where:\n    A. \"x\" goes to \"<v>\"\n\n";
+        let bound = format!("{template}Always:\n1. For each \"y\" marked y:\n    A. There is a \"flows to y\" where:\n        a. \"y\" goes to \"flows to y\"");
+        assert!(unbound(&bound).is_empty());
+
+        let unbound_argument = format!("{template}Always:\n1. For each \"flows to z\":\n    A. \"flows to z\" is marked y");
+        assert_eq!(unbound(&unbound_argument), vec!["z"]);
+
+        // the template's own "x" would capture the argument
+        let captured = format!("{template}Always:\n1. For each \"x\" marked y:\n    A. There is a \"flows to x\" where:\n        a. \"x\" goes to \"flows to x\"");
+        let found = problems(&captured);
+        assert!(matches!(
+            found.as_slice(),
+            [Problem::Captured((var, inner, template))]
+                if var.name == "flows to x" && inner.location.line == 2 && template.name == "flows to <v>"
+        ));
+
+        let ambiguous = "Definitions:\n1. \"<a> to b\" is each \"x\" marked <a> where:\n    A. \"x\" is marked y\n2. \"a to <c>\" is each \"x\" marked <c> where:\n    A. \"x\" is marked y\n\nAlways:\n1. For each \"a to b\":\n    A. \"a to b\" is marked z";
+        let found = problems(ambiguous);
+        assert!(matches!(
+            found.as_slice(),
+            [Problem::Ambiguous((var, first, second))]
+                if var.name == "a to b" && first.name == "<a> to b" && second.name == "a to <c>"
+        ));
+    }
+}
@@ -1,47 +1,119 @@
 use nom::{
     branch::alt,
     error::context,
-    sequence::{tuple, delimited}, character::complete::multispace0, bytes::complete::tag,
+    sequence::{tuple, delimited, preceded}, bytes::complete::tag,
+    character::complete::{space0, space1}, combinator::{cut, map}, multi::separated_list1,
 };
 
 use crate::{
-    PolicyScope, Res, common::*,
+    ControllerSelector, PolicyScope, Res, Span, common::*,
 };
 
 
-fn always(s: &str) -> Res<&str, PolicyScope> {
+fn always(s: Span) -> Res<Span, PolicyScope> {
     let mut combinator = context(
         "always",
-        tuple((multispace0, tag("Always"), colon)),
+        tuple((multispace_comment0, tag("Always"), colon)),
     );
     let (remainder, _) = combinator(s)?;
     Ok((remainder, PolicyScope::Always))
 }
 
-fn sometimes(s: &str) -> Res<&str, PolicyScope> {
+fn sometimes(s: Span) -> Res<Span, PolicyScope> {
     let mut combinator = context(
         "sometimes",
-        tuple((multispace0, tag("Sometimes"), colon)),
+        tuple((multispace_comment0, tag("Sometimes"), colon)),
     );
     let (remainder, _) = combinator(s)?;
     Ok((remainder, PolicyScope::Sometimes))
 }
 
-fn in_ctrler<'a>(s: &'a str) -> Res<&str, PolicyScope<'a>> {
+// gdpr_deletes, account_purge
+fn controller_names<'a>(s: Span<'a>) -> Res<Span<'a>, Vec<&'a str>> {
+    context(
+        "controller names",
+        separated_list1(
+            tuple((space0, tag(","))),
+            map(identifier, |name: Span<'a>| *name.fragment())
+        )
+    )(s)
+}
+
+fn except<'a>(s: Span<'a>) -> Res<Span<'a>, ControllerSelector<'a>> {
+    preceded(
+        tuple((tag("all controllers except"), space1)),
+        cut(map(controller_names, ControllerSelector::Except))
+    )(s)
+}
+
+fn matching<'a>(s: Span<'a>) -> Res<Span<'a>, ControllerSelector<'a>> {
+    preceded(
+        tuple((tag("controllers matching"), space1)),
+        cut(map(
+            context("pattern", delimited(tag("\""), quoted_name, tag("\""))),
+            |pattern: Span<'a>| ControllerSelector::Matching(pattern.fragment())
+        ))
+    )(s)
+}
+
+fn in_ctrler<'a>(s: Span<'a>) -> Res<Span<'a>, PolicyScope<'a>> {
     let mut combinator = context(
         "in ctrler",
         delimited(
-            tuple((multispace0, tag("In"))),
-            alphabetic_with_underscores, 
+            tuple((multispace_comment0, tag("In"), space1)),
+            // a controller could be named "all" or "controllers", so try the longer forms first
+            alt((except, matching, map(controller_names, ControllerSelector::Named))),
             colon,
         )
     );
-    let (remainder, ctrler) = combinator(s)?;
-    Ok((remainder, PolicyScope::InCtrler(ctrler)))
+    let (remainder, selector) = combinator(s)?;
+    Ok((remainder, PolicyScope::InCtrler(selector)))
 }
 
-pub fn scope(s: &str) -> Res<&str, PolicyScope> {
+pub fn scope(s: Span) -> Res<Span, PolicyScope> {
     context("scope", 
         alt((always, sometimes, in_ctrler))
     )(s)
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use nom::combinator::all_consuming;
+
+    use super::*;
+    use crate::DEFAULT_CONFIG;
+
+    fn parsed(s: &str) -> Option<PolicyScope<'_>> {
+        all_consuming(scope)(Span::new_extra(s, &DEFAULT_CONFIG)).ok().map(|(_, scope)| scope)
+    }
+
+    #[test]
+    pub fn test_scope() {
+        assert_eq!(parsed("Always:"), Some(PolicyScope::Always));
+        assert_eq!(parsed("\n# rationale\nSometimes:\n"), Some(PolicyScope::Sometimes));
+        assert_eq!(parsed("In gdpr_deletes:"), Some(PolicyScope::InCtrler(ControllerSelector::Named(vec!["gdpr_deletes"]))));
+        assert_eq!(
+            parsed("In gdpr_deletes , account_purge:"),
+            Some(PolicyScope::InCtrler(ControllerSelector::Named(vec!["gdpr_deletes", "account_purge"])))
+        );
+        assert_eq!(
+            parsed("In controllers matching \"*_deletes\":"),
+            Some(PolicyScope::InCtrler(ControllerSelector::Matching("*_deletes")))
+        );
+        assert_eq!(
+            parsed("In all controllers except gdpr_deletes, account_purge:"),
+            Some(PolicyScope::InCtrler(ControllerSelector::Except(vec!["gdpr_deletes", "account_purge"])))
+        );
+        // controllers can be named like the keywords
+        assert_eq!(parsed("In all, controllers:"), Some(PolicyScope::InCtrler(ControllerSelector::Named(vec!["all", "controllers"]))));
+    }
+
+    #[test]
+    pub fn test_malformed_scope() {
+        assert_eq!(parsed("always:"), None);
+        assert_eq!(parsed("In :"), None);
+        assert_eq!(parsed("In gdpr_deletes,:"), None);
+        assert_eq!(parsed("In controllers matching *_deletes:"), None);
+        assert_eq!(parsed("In all controllers except:"), None);
+        assert_eq!(parsed("In gdpr_deletes"), None);
+    }
+}
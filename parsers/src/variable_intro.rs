@@ -6,10 +6,10 @@ use nom::{
 };
 
 use crate::{
-    VariableIntro, Res, common::*,
+    VariableIntro, Res, Span, common::*,
 };
 
-pub fn variable_def<'a>(s: &'a str) -> Res<&str, VariableIntro<'a>> {
+pub fn variable_def<'a>(s: Span<'a>) -> Res<Span<'a>, VariableIntro<'a>> {
     let mut combinator = context(
         "variable (introduction)",
         variable
@@ -21,10 +21,10 @@ pub fn variable_def<'a>(s: &'a str) -> Res<&str, VariableIntro<'a>> {
     ))
 }
 
-pub fn variable_marked<'a>(s: &'a str) -> Res<&str, VariableIntro<'a>> {
+pub fn variable_marked<'a>(s: Span<'a>) -> Res<Span<'a>, VariableIntro<'a>> {
     let mut combinator = context(
         "variable marked",
-        separated_pair(variable, tag("marked"), marker)
+        separated_pair(variable, tag("marked"), marker_expr)
     );
     let (remainder, (variable, marker)) = combinator(s)?;
     Ok((
@@ -33,10 +33,10 @@ pub fn variable_marked<'a>(s: &'a str) -> Res<&str, VariableIntro<'a>> {
     ))
 } 
 
-fn variable_type_marked<'a>(s: &'a str) -> Res<&str, VariableIntro<'a>> {
+fn variable_type_marked<'a>(s: Span<'a>) -> Res<Span<'a>, VariableIntro<'a>> {
     let mut combinator = context(
         "variable type marked",
-        separated_pair(variable, tag("type marked"), marker),
+        separated_pair(variable, tag("type marked"), marker_expr),
     );
     let (remainder, (variable, marker)) = combinator(s)?;
     Ok((
@@ -45,7 +45,7 @@ fn variable_type_marked<'a>(s: &'a str) -> Res<&str, VariableIntro<'a>> {
     ))
 }
 
-fn variable_source_of<'a>(s: &'a str) -> Res<&str, VariableIntro<'a>> {
+fn variable_source_of<'a>(s: Span<'a>) -> Res<Span<'a>, VariableIntro<'a>> {
     let mut combinator = context(
         "variable source of",
         separated_pair(variable, tag("that is a source of"), variable)
@@ -57,7 +57,7 @@ fn variable_source_of<'a>(s: &'a str) -> Res<&str, VariableIntro<'a>> {
     ))
 }
 
-fn roots<'a>(s: &'a str) -> Res<&str, VariableIntro<'a>> {
+fn roots<'a>(s: Span<'a>) -> Res<Span<'a>, VariableIntro<'a>> {
     let mut combinator = context(
         "roots",
         tag("input")
@@ -69,7 +69,7 @@ fn roots<'a>(s: &'a str) -> Res<&str, VariableIntro<'a>> {
     ))
 }
 
-pub fn variable_intro<'a>(s: &'a str) -> Res<&str, VariableIntro<'a>> {
+pub fn variable_intro<'a>(s: Span<'a>) -> Res<Span<'a>, VariableIntro<'a>> {
     context(
         "variable intro",
         delimited(
@@ -84,4 +84,51 @@ pub fn variable_intro<'a>(s: &'a str) -> Res<&str, VariableIntro<'a>> {
             )), 
             space0)
     )(s)
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use nom::combinator::all_consuming;
+
+    use super::*;
+    use crate::{MarkerExpr, Location, DEFAULT_CONFIG};
+
+    fn parsed(s: &str) -> Option<VariableIntro<'_>> {
+        all_consuming(variable_intro)(Span::new_extra(s, &DEFAULT_CONFIG)).ok().map(|(_, intro)| intro)
+    }
+
+    // The intro's markers by name, and whether they're joined by `and`, `or` or neither
+    fn markers<'a>(intro: &VariableIntro<'a>) -> (&'static str, Vec<&'a str>) {
+        let expr = match intro {
+            VariableIntro::VariableMarked((_, expr)) | VariableIntro::VariableOfTypeMarked((_, expr)) => expr,
+            intro => panic!("expected a marked intro, got {intro:?}"),
+        };
+        let kind = match expr {
+            MarkerExpr::Marker(_) => "marker",
+            MarkerExpr::And(_) => "and",
+            MarkerExpr::Or(_) => "or",
+        };
+        (kind, expr.markers().iter().map(|marker| marker.name).collect())
+    }
+
+    #[test]
+    pub fn test_marker_expr() {
+        let single = parsed("\"a\" marked hash").unwrap();
+        assert_eq!(markers(&single), ("marker", vec!["hash"]));
+
+        let or = parsed("\"a\" marked hash or apikey_response").unwrap();
+        assert_eq!(markers(&or), ("or", vec!["hash", "apikey_response"]));
+
+        let and = parsed("\"a\" type marked sensitive and stored and encrypted").unwrap();
+        assert!(matches!(and, VariableIntro::VariableOfTypeMarked(_)));
+        assert_eq!(markers(&and), ("and", vec!["sensitive", "stored", "encrypted"]));
+    }
+
+    #[test]
+    pub fn test_malformed_marker_expr() {
+        assert_eq!(parsed("\"a\" marked"), None);
+        assert_eq!(parsed("\"a\" marked hash or"), None);
+
+        let mixed = "Always:\n1. For each \"a\" marked hash and stored or sensitive:\n    A. \"a\" is marked y";
+        assert_eq!(parse_failure(mixed), Some(("mixed operators", Location { line: 2, column: 40 })));
+    }
+}